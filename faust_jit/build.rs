@@ -36,6 +36,9 @@ fn main() {
         // included header files changed.
         .rustified_enum("WWidgetDeclType")
         .rustified_enum("WMidiSyncMsg")
+        .rustified_enum("WNoteExpression")
+        .rustified_enum("WVoicePolicy")
+        .rustified_enum("WVoiceActivity")
         .parse_callbacks(Box::new(bindgen::CargoCallbacks::new()))
         // Finish the builder and generate the bindings.
         .generate()
@@ -5,13 +5,18 @@
 #include <faust/dsp/llvm-dsp.h>
 
 #include <faust/dsp/poly-dsp.h>
+#include <atomic>
+#include <algorithm>
+#include <cmath>
 #include <iostream>
+#include <map>
 #include <faust/dsp/poly-llvm-dsp.h>
 
 #include <faust/dsp/timed-dsp.h>
 
 #include <faust/midi/midi.h>
 #include <faust/gui/MidiUI.h>
+#include <faust/gui/DecoratorUI.h>
 
 #ifdef DEFINE_FAUST_STATIC_VARS
 // These static vars must be declared in the application code. See
@@ -48,6 +53,666 @@ void w_deleteDSPFactory(WFactory *factory)
     delete factory;
 }
 
+// Finds the zones of a voice that per-note expressions should be sent to (ie.
+// those with an [mpe:xxx] metadata)
+class NoteExprZonesUI : public GenericUI
+{
+private:
+    std::map<FAUSTFLOAT *, std::string> fDeclared;
+
+    void addZone(FAUSTFLOAT *zone, FAUSTFLOAT min, FAUSTFLOAT max)
+    {
+        auto it = fDeclared.find(zone);
+        if (it == fDeclared.end())
+            return;
+        static const char *names[] = {"pressure", "timbre", "pan", "vibrato", "expression"};
+        for (int expr = 0; expr < NOTE_VOLUME; expr++)
+        {
+            if (it->second == names[expr])
+                fZones[expr].push_back({zone, min, max});
+        }
+    }
+
+public:
+    struct RangedZone
+    {
+        FAUSTFLOAT *fZone;
+        FAUSTFLOAT fMin;
+        FAUSTFLOAT fMax;
+
+        // `value` is between 0 and 1
+        void set(float value) { *fZone = fMin + value * (fMax - fMin); }
+    };
+
+    // For each expression that is mapped to widgets (ie. those before
+    // NOTE_VOLUME)
+    std::vector<RangedZone> fZones[NOTE_VOLUME];
+
+    void addButton(const char *label, FAUSTFLOAT *zone) { addZone(zone, 0, 1); }
+    void addCheckButton(const char *label, FAUSTFLOAT *zone) { addZone(zone, 0, 1); }
+    void addVerticalSlider(const char *label, FAUSTFLOAT *zone, FAUSTFLOAT init, FAUSTFLOAT min, FAUSTFLOAT max, FAUSTFLOAT step) { addZone(zone, min, max); }
+    void addHorizontalSlider(const char *label, FAUSTFLOAT *zone, FAUSTFLOAT init, FAUSTFLOAT min, FAUSTFLOAT max, FAUSTFLOAT step) { addZone(zone, min, max); }
+    void addNumEntry(const char *label, FAUSTFLOAT *zone, FAUSTFLOAT init, FAUSTFLOAT min, FAUSTFLOAT max, FAUSTFLOAT step) { addZone(zone, min, max); }
+
+    void declare(FAUSTFLOAT *zone, const char *key, const char *value)
+    {
+        if (zone && strcmp(key, "mpe") == 0)
+            fDeclared[zone] = value;
+    }
+};
+
+// Faust's mydsp_poly, with our own voice allocation, so that we know which
+// note (MIDI channel and note id) each voice is playing. This is needed to
+// support MPE and per-note expressions
+struct WPolyDsp : public mydsp_poly
+{
+    // What we need to know about each voice, in addition to its dsp_voice
+    struct VoiceInfo
+    {
+        // The note the voice plays or last played (-1 if none). The pitch is
+        // still known once the voice is released, contrary to fCurNote
+        int fChannel = -1;
+        int fPitch = -1;
+        int fNoteId = -1;
+        int fVelocity = 0;
+        // The sample (counted since the DSP started) at which the note started
+        int64_t fStartSample = 0;
+        // The gain given by the velocity of the note
+        float fVelGain = 0;
+        float fTuning = 0;
+        float fVolume = 1;
+        std::vector<FAUSTFLOAT *> fFreqZones;
+        std::vector<FAUSTFLOAT *> fGainZones;
+        NoteExprZonesUI fExprZones;
+
+        // What the voice was doing at the end of the last computed buffer, to
+        // be read by other threads
+        std::atomic<int> fPublishedActivity{VOICE_FREE};
+        std::atomic<int> fPublishedPitch{0};
+        std::atomic<int> fPublishedVelocity{0};
+        std::atomic<int64_t> fPublishedStartSample{0};
+    };
+
+    std::vector<VoiceInfo> fVoiceInfos;
+    // Used to know which voices are the oldest ones
+    int fNextDate = 0;
+    // How many samples have been computed so far
+    std::atomic<int64_t> fSampleCount{0};
+    // The frequency of each MIDI note (see w_setTuning)
+    std::atomic<double> fNoteFreqs[128];
+    // The id to give to the next note that starts (see w_setNextNoteId)
+    int fNextNoteId = -1;
+    // The id of the next note to release (see w_setNextNoteOffId)
+    int fNextNoteOffId = -1;
+
+    // The notes whose voice was freed or stolen, and that w_popTerminatedNotes
+    // has not reported yet
+    static const int kMaxTerminated = 256;
+    WNoteRef fTerminated[kMaxTerminated];
+    int fNumTerminated = 0;
+
+    WVoicePolicy fPolicy = STEAL_OLDEST;
+    bool fCutOnRelease = false;
+    // In mono modes, the notes currently held, the last one being the one that
+    // plays
+    struct HeldNote
+    {
+        int fChannel;
+        int fPitch;
+        int fVelocity;
+        int fNoteId;
+    };
+    static const int kMaxHeld = 128;
+    HeldNote fHeld[kMaxHeld];
+    int fNumHeld = 0;
+
+    bool fMpe = false;
+    WMpeConfig fMpeConfig;
+    // The current state of each channel, as set by its last MPE messages. Bends
+    // are between -1 and 1, pressures and timbres between 0 and 1
+    float fChanBend[16];
+    float fChanPressure[16];
+    float fChanTimbre[16];
+
+    WPolyDsp(dsp *voice_dsp, int nvoices, bool control, bool group)
+        : mydsp_poly(voice_dsp, nvoices, control, group), fVoiceInfos(fVoiceTable.size())
+    {
+        for (size_t i = 0; i < fVoiceTable.size(); i++)
+        {
+            dsp_voice *voice = fVoiceTable[i];
+            for (const auto &path : voice->fFreqPath)
+                fVoiceInfos[i].fFreqZones.push_back(voice->getParamZone(path));
+            for (const auto &path : voice->fGainPath)
+                fVoiceInfos[i].fGainZones.push_back(voice->getParamZone(path));
+            voice->buildUserInterface(&fVoiceInfos[i].fExprZones);
+            // Also used by Faust when a stolen voice starts its new note, so
+            // that note gets its bends and tuning right away:
+            voice->fKeyFun = [this, i](int pitch)
+            { return voiceFreq(i, pitch); };
+        }
+        setTuning(nullptr);
+        resetChannels();
+    }
+
+    void setTuning(const double *freqs)
+    {
+        for (int i = 0; i < 128; i++)
+            fNoteFreqs[i] = freqs ? freqs[i] : dsp_voice::midiToFreq(i);
+    }
+
+    void resetChannels()
+    {
+        for (int i = 0; i < 16; i++)
+        {
+            fChanBend[i] = 0;
+            fChanPressure[i] = 0;
+            fChanTimbre[i] = 0.5;
+        }
+    }
+
+    int masterChannel() { return fMpeConfig.lower_zone ? 0 : 15; }
+
+    bool isMemberChannel(int channel)
+    {
+        if (fMpeConfig.lower_zone)
+            return channel >= 1 && channel <= fMpeConfig.member_channels;
+        else
+            return channel <= 14 && channel >= 15 - fMpeConfig.member_channels;
+    }
+
+    void terminate(int i)
+    {
+        VoiceInfo &info = fVoiceInfos[i];
+        if (info.fPitch >= 0 && fNumTerminated < kMaxTerminated)
+            fTerminated[fNumTerminated++] = {info.fNoteId, info.fChannel, info.fPitch};
+        info.fPitch = -1;
+    }
+
+    // Whether voice `a` should be stolen rather than voice `b`
+    bool stealBefore(int a, int b)
+    {
+        switch (fPolicy)
+        {
+        case STEAL_QUIETEST:
+            return fVoiceTable[a]->fLevel < fVoiceTable[b]->fLevel;
+        case STEAL_LOWEST:
+            return fVoiceInfos[a].fPitch < fVoiceInfos[b].fPitch;
+        case STEAL_HIGHEST:
+            return fVoiceInfos[a].fPitch > fVoiceInfos[b].fPitch;
+        default:
+            return fVoiceTable[a]->fDate < fVoiceTable[b]->fDate;
+        }
+    }
+
+    // Looks for a free voice, else steals a released voice, else a playing
+    // voice. Which one is stolen depends on the policy
+    int allocVoice()
+    {
+        int free_voice = kNoVoice;
+        int release_voice = kNoVoice;
+        int playing_voice = kNoVoice;
+        // In mono modes, only the first voice is used:
+        int nvoices = isMono() ? 1 : (int)fVoiceTable.size();
+        for (int i = 0; i < nvoices; i++)
+        {
+            dsp_voice *voice = fVoiceTable[i];
+            if (voice->fCurNote == kFreeVoice)
+            {
+                free_voice = i;
+                break;
+            }
+            int &candidate = voice->fCurNote == kReleaseVoice ? release_voice : playing_voice;
+            if (candidate == kNoVoice || stealBefore(i, candidate))
+                candidate = i;
+        }
+        int voice = free_voice != kNoVoice ? free_voice : release_voice != kNoVoice ? release_voice
+                                                                                    : playing_voice;
+        terminate(voice);
+        // A stolen voice will first fade out, and then play the new note:
+        fVoiceTable[voice]->fCurNote = voice == free_voice ? kActiveVoice : kLegatoVoice;
+        fVoiceTable[voice]->fDate = fNextDate++;
+        return voice;
+    }
+
+    // Whether voice `i` plays (or releases) the given note. A negative note_id
+    // matches any note id
+    bool playsNote(int i, int note_id, int channel, int pitch)
+    {
+        const VoiceInfo &info = fVoiceInfos[i];
+        if (info.fPitch < 0)
+            return false;
+        if (note_id >= 0)
+            return info.fNoteId == note_id;
+        return info.fPitch == pitch && (!fMpe || info.fChannel == channel);
+    }
+
+    // The frequency of a pitch played by voice `i`, with the bends and tuning
+    // of the note it plays
+    double voiceFreq(int i, int pitch)
+    {
+        VoiceInfo &info = fVoiceInfos[i];
+        float semitones = info.fTuning;
+        if (fMpe)
+        {
+            semitones += fChanBend[masterChannel()] * fMpeConfig.master_pitch_bend_range;
+            if (isMemberChannel(info.fChannel))
+                semitones += fChanBend[info.fChannel] * fMpeConfig.pitch_bend_range;
+        }
+        return fNoteFreqs[std::min(std::max(pitch, 0), 127)] * std::pow(2.0, semitones / 12.0);
+    }
+
+    // A stolen voice (kLegatoVoice) still plays its old note until Faust
+    // starts the new one, with the frequency given by voiceFreq
+    void applyPitch(int i)
+    {
+        dsp_voice *voice = fVoiceTable[i];
+        if (voice->fCurNote < 0)
+            return;
+        double freq = voiceFreq(i, voice->fCurNote);
+        for (auto zone : fVoiceInfos[i].fFreqZones)
+            *zone = freq;
+    }
+
+    void setExpression(int i, WNoteExpression expr, float value)
+    {
+        VoiceInfo &info = fVoiceInfos[i];
+        switch (expr)
+        {
+        case NOTE_TUNING:
+            info.fTuning = value;
+            applyPitch(i);
+            break;
+        case NOTE_VOLUME:
+            info.fVolume = value;
+            if (fVoiceTable[i]->fCurNote >= 0)
+            {
+                for (auto zone : info.fGainZones)
+                    *zone = info.fVelGain * value;
+            }
+            break;
+        default:
+            for (auto &zone : info.fExprZones.fZones[expr])
+                zone.set(value);
+        }
+    }
+
+    bool isMono() { return fPolicy == MONO_LEGATO || fPolicy == MONO_RETRIGGER; }
+
+    // Makes voice `i`, as returned by allocVoice, play a note
+    void startNote(int i, int channel, int pitch, int velocity, int note_id)
+    {
+        dsp_voice *voice = fVoiceTable[i];
+        voice->keyOn(pitch, velocity, voice->fCurNote == kLegatoVoice);
+        VoiceInfo &info = fVoiceInfos[i];
+        info.fVelGain = voice->fVelFun(velocity);
+        info.fVelocity = velocity;
+        info.fStartSample = fSampleCount;
+        setNote(i, channel, pitch, note_id);
+    }
+
+    void setNote(int i, int channel, int pitch, int note_id)
+    {
+        VoiceInfo &info = fVoiceInfos[i];
+        info.fChannel = channel;
+        info.fPitch = pitch;
+        info.fNoteId = note_id;
+        info.fTuning = 0;
+        info.fVolume = 1;
+        applyPitch(i);
+        if (fMpe && isMemberChannel(channel))
+        {
+            setExpression(i, NOTE_PRESSURE, fChanPressure[channel]);
+            setExpression(i, NOTE_TIMBRE, fChanTimbre[channel]);
+        }
+    }
+
+    // Makes the single voice used in mono modes play a note
+    void startMonoNote(const HeldNote &note)
+    {
+        dsp_voice *voice = fVoiceTable[0];
+        if (fPolicy == MONO_LEGATO && voice->fCurNote >= 0)
+        {
+            // The voice keeps playing, only its pitch changes:
+            terminate(0);
+            voice->fCurNote = note.fPitch;
+            setNote(0, note.fChannel, note.fPitch, note.fNoteId);
+        }
+        else
+        {
+            // The voice is retriggered (with the same fade out as when
+            // stealing it):
+            startNote(allocVoice(), note.fChannel, note.fPitch, note.fVelocity, note.fNoteId);
+        }
+    }
+
+    MapUI *keyOn(int channel, int pitch, int velocity)
+    {
+        if (fVoiceTable.empty())
+            return nullptr;
+        int note_id = fNextNoteId;
+        fNextNoteId = -1;
+        if (!isMono())
+        {
+            int i = allocVoice();
+            startNote(i, channel, pitch, velocity, note_id);
+            return fVoiceTable[i];
+        }
+        if (fNumHeld == kMaxHeld)
+        {
+            std::copy(fHeld + 1, fHeld + kMaxHeld, fHeld);
+            fNumHeld--;
+        }
+        fHeld[fNumHeld++] = {channel, pitch, velocity, note_id};
+        startMonoNote(fHeld[fNumHeld - 1]);
+        return fVoiceTable[0];
+    }
+
+    void keyOff(int channel, int pitch, int velocity)
+    {
+        int note_id = fNextNoteOffId;
+        fNextNoteOffId = -1;
+        if (isMono())
+        {
+            // Forgetting the note, and if it was the one playing, going back
+            // to the previous note still held:
+            for (int h = fNumHeld - 1; h >= 0; h--)
+            {
+                bool same_note = note_id >= 0 ? fHeld[h].fNoteId == note_id
+                                              : fHeld[h].fPitch == pitch && (!fMpe || fHeld[h].fChannel == channel);
+                if (same_note)
+                {
+                    bool was_playing = h == fNumHeld - 1;
+                    std::copy(fHeld + h + 1, fHeld + fNumHeld, fHeld + h);
+                    fNumHeld--;
+                    if (!was_playing)
+                        return;
+                    if (fNumHeld > 0)
+                    {
+                        startMonoNote(fHeld[fNumHeld - 1]);
+                        return;
+                    }
+                    break;
+                }
+            }
+        }
+        for (int i = 0; i < (int)fVoiceTable.size(); i++)
+        {
+            dsp_voice *voice = fVoiceTable[i];
+            if ((voice->fCurNote >= 0 || voice->fCurNote == kLegatoVoice) && playsNote(i, note_id, channel, pitch))
+            {
+                if (fCutOnRelease)
+                {
+                    voice->keyOff(true);
+                    voice->instanceClear();
+                    terminate(i);
+                }
+                else
+                    voice->keyOff();
+                return;
+            }
+        }
+    }
+
+    // Releases all the voices, or if `hard` silences them immediately (also
+    // clearing their state, so nothing of their tails is heard later)
+    void allNotesOff(bool hard)
+    {
+        fNumHeld = 0;
+        for (int i = 0; i < (int)fVoiceTable.size(); i++)
+        {
+            dsp_voice *voice = fVoiceTable[i];
+            if (hard)
+            {
+                voice->keyOff(true);
+                voice->instanceClear();
+                terminate(i);
+            }
+            else if (voice->fCurNote >= 0 || voice->fCurNote == kLegatoVoice)
+                voice->keyOff();
+        }
+    }
+
+    void pitchWheel(int channel, int wheel)
+    {
+        if (!fMpe || !(channel == masterChannel() || isMemberChannel(channel)))
+            return;
+        fChanBend[channel] = (wheel - 8192) / 8192.f;
+        for (int i = 0; i < (int)fVoiceTable.size(); i++)
+        {
+            if (channel == masterChannel() || fVoiceInfos[i].fChannel == channel)
+                applyPitch(i);
+        }
+    }
+
+    void chanPress(int channel, int press)
+    {
+        if (!fMpe || !isMemberChannel(channel))
+            return;
+        fChanPressure[channel] = press / 127.f;
+        for (int i = 0; i < (int)fVoiceTable.size(); i++)
+        {
+            if (fVoiceInfos[i].fChannel == channel)
+                setExpression(i, NOTE_PRESSURE, fChanPressure[channel]);
+        }
+    }
+
+    void ctrlChange(int channel, int ctrl, int value)
+    {
+        // Faust just releases the voices on both All Sound Off (CC120) and All
+        // Notes Off (CC123), and does not know about the held notes of mono
+        // modes:
+        if (ctrl == 120 || ctrl == 123)
+        {
+            allNotesOff(ctrl == 120);
+            return;
+        }
+        mydsp_poly::ctrlChange(channel, ctrl, value);
+        if (!fMpe || ctrl != 74 || !isMemberChannel(channel))
+            return;
+        fChanTimbre[channel] = value / 127.f;
+        for (int i = 0; i < (int)fVoiceTable.size(); i++)
+        {
+            if (fVoiceInfos[i].fChannel == channel)
+                setExpression(i, NOTE_TIMBRE, fChanTimbre[channel]);
+        }
+    }
+
+    void compute(int count, FAUSTFLOAT **inputs, FAUSTFLOAT **outputs)
+    {
+        mydsp_poly::compute(count, inputs, outputs);
+        fSampleCount += count;
+        for (int i = 0; i < (int)fVoiceTable.size(); i++)
+        {
+            VoiceInfo &info = fVoiceInfos[i];
+            int cur_note = fVoiceTable[i]->fCurNote;
+            // Finding the voices that Faust freed once their release ended:
+            if (cur_note == kFreeVoice)
+                terminate(i);
+            info.fPublishedActivity = cur_note == kFreeVoice      ? VOICE_FREE
+                                      : cur_note == kReleaseVoice ? VOICE_RELEASING
+                                                                  : VOICE_PLAYING;
+            info.fPublishedPitch = info.fPitch;
+            info.fPublishedVelocity = info.fVelocity;
+            info.fPublishedStartSample = info.fStartSample;
+        }
+    }
+};
+
+// Gives a ringbuffer to every input zone of a DSP, in a map that belongs to
+// that DSP only. The map is filled once, when the DSP is created, so that
+// changes of value can then be queued from the audio thread without modifying
+// it. GUI::gTimedZoneMap is not used, as it is shared by all the DSPs of the
+// process (including those being created or deleted by other threads)
+class TimedZonesUI : public GenericUI
+{
+private:
+    std::map<FAUSTFLOAT *, ringbuffer_t *> &fZones;
+
+    void addTimedZone(FAUSTFLOAT *zone)
+    {
+        if (fZones.find(zone) == fZones.end())
+            fZones[zone] = ringbuffer_create(8192);
+    }
+
+public:
+    TimedZonesUI(std::map<FAUSTFLOAT *, ringbuffer_t *> &zones) : fZones(zones) {}
+
+    void addButton(const char *label, FAUSTFLOAT *zone) { addTimedZone(zone); }
+    void addCheckButton(const char *label, FAUSTFLOAT *zone) { addTimedZone(zone); }
+    void addVerticalSlider(const char *label, FAUSTFLOAT *zone, FAUSTFLOAT init, FAUSTFLOAT min, FAUSTFLOAT max, FAUSTFLOAT step) { addTimedZone(zone); }
+    void addHorizontalSlider(const char *label, FAUSTFLOAT *zone, FAUSTFLOAT init, FAUSTFLOAT min, FAUSTFLOAT max, FAUSTFLOAT step) { addTimedZone(zone); }
+    void addNumEntry(const char *label, FAUSTFLOAT *zone, FAUSTFLOAT init, FAUSTFLOAT min, FAUSTFLOAT max, FAUSTFLOAT step) { addTimedZone(zone); }
+};
+
+// Makes the MidiUI of a DSP send the MIDI messages of the widgets that changed,
+// dated at sample `date` of the current buffer
+static void flushMidiOutput(WUIs *uis, int date);
+
+// Applies the changes of value queued with w_scheduleZoneChange at the right
+// sample, by splitting the computation of the DSP it wraps into slices. It is
+// itself wrapped in the timed_dsp (which deals with the MIDI controls), and
+// therefore gets the slices computed by the timed_dsp, which it tracks the
+// position of within the whole buffer
+class ScheduledZonesDsp : public decorator_dsp
+{
+private:
+    static const int kMaxChanges = 1024;
+
+    struct Change
+    {
+        int fDate;
+        FAUSTFLOAT *fZone;
+        FAUSTFLOAT fValue;
+    };
+
+    // Sorted by date. The changes dated after the current buffer stay there
+    // for the next ones
+    Change fChanges[kMaxChanges];
+    int fNumChanges = 0;
+    int fNextChange = 0;
+    // The length of the current buffer
+    int fCount = 0;
+    // Where the next slice starts within the whole buffer
+    int fPos = 0;
+    std::vector<FAUSTFLOAT *> fInputs;
+    std::vector<FAUSTFLOAT *> fOutputs;
+
+public:
+    std::map<FAUSTFLOAT *, ringbuffer_t *> fZones;
+    // Whose MIDI output is flushed after each slice (set by w_createUIs)
+    WUIs *fUis = nullptr;
+
+    ScheduledZonesDsp(dsp *dsp) : decorator_dsp(dsp)
+    {
+        TimedZonesUI zones_ui(fZones);
+        dsp->buildUserInterface(&zones_ui);
+        fInputs.resize(dsp->getNumInputs());
+        fOutputs.resize(dsp->getNumOutputs());
+    }
+
+    ~ScheduledZonesDsp()
+    {
+        for (auto &it : fZones)
+            ringbuffer_free(it.second);
+    }
+
+    bool schedule(FAUSTFLOAT *zone, double time, FAUSTFLOAT value)
+    {
+        auto it = fZones.find(zone);
+        if (it == fZones.end() || ringbuffer_write_space(it->second) < sizeof(DatedControl))
+            return false;
+        DatedControl dated_val(time, value);
+        ringbuffer_write(it->second, (const char *)&dated_val, sizeof(DatedControl));
+        return true;
+    }
+
+    // Collects the changes queued for a buffer of `count` samples, to be
+    // called before the buffer is computed. The changes dated after the
+    // previous buffer are kept, and dated from the start of this one. Once
+    // kMaxChanges changes are collected, the others wait in their ringbuffers
+    // for the next buffer. Does not allocate
+    void beginBuffer(int count)
+    {
+        int carried = 0;
+        for (int i = fNextChange; i < fNumChanges; i++)
+        {
+            fChanges[carried] = fChanges[i];
+            fChanges[carried].fDate -= fCount;
+            carried++;
+        }
+        fNumChanges = carried;
+        fNextChange = 0;
+        fCount = count;
+        fPos = 0;
+        for (auto &it : fZones)
+        {
+            DatedControl dated_val;
+            while (fNumChanges < kMaxChanges && ringbuffer_read_space(it.second) >= sizeof(DatedControl))
+            {
+                ringbuffer_read(it.second, (char *)&dated_val, sizeof(DatedControl));
+                int date = (int)std::max(0.0, std::min(1e9, dated_val.fDate));
+                fChanges[fNumChanges++] = {date, it.first, dated_val.fValue};
+            }
+        }
+        // Insertion sort, keeping the changes of a same date in the order
+        // they were queued for each zone (std::stable_sort may allocate):
+        for (int i = 1; i < fNumChanges; i++)
+        {
+            Change change = fChanges[i];
+            int j = i;
+            for (; j > 0 && fChanges[j - 1].fDate > change.fDate; j--)
+                fChanges[j] = fChanges[j - 1];
+            fChanges[j] = change;
+        }
+    }
+
+    void compute(int count, FAUSTFLOAT **inputs, FAUSTFLOAT **outputs)
+    {
+        int end = fPos + count;
+        while (true)
+        {
+            while (fNextChange < fNumChanges && fChanges[fNextChange].fDate <= fPos && fChanges[fNextChange].fDate < fCount)
+            {
+                *fChanges[fNextChange].fZone = fChanges[fNextChange].fValue;
+                fNextChange++;
+            }
+            int slice_end = fNextChange < fNumChanges ? std::min(end, fChanges[fNextChange].fDate) : end;
+            if (slice_end <= fPos)
+                break;
+            int offset = count - (end - fPos);
+            for (int i = 0; i < (int)fInputs.size(); i++)
+                fInputs[i] = inputs[i] + offset;
+            for (int i = 0; i < (int)fOutputs.size(); i++)
+                fOutputs[i] = outputs[i] + offset;
+            fDSP->compute(slice_end - fPos, fInputs.data(), fOutputs.data());
+            fPos = slice_end;
+            if (fUis)
+                flushMidiOutput(fUis, fPos - 1);
+        }
+    }
+
+    void compute(double date_usec, int count, FAUSTFLOAT **inputs, FAUSTFLOAT **outputs)
+    {
+        compute(count, inputs, outputs);
+    }
+};
+
+// A timed_dsp that remembers the poly DSP it wraps, and the DSP applying the
+// changes queued with w_scheduleZoneChange
+struct WTimedDsp : public timed_dsp
+{
+    WPolyDsp *fPoly;
+    ScheduledZonesDsp *fScheduled;
+
+    WTimedDsp(ScheduledZonesDsp *scheduled, WPolyDsp *poly) : timed_dsp(scheduled), fPoly(poly), fScheduled(scheduled) {}
+
+    void compute(double date_usec, int count, FAUSTFLOAT **inputs, FAUSTFLOAT **outputs)
+    {
+        fScheduled->beginBuffer(count);
+        timed_dsp::compute(date_usec, count, inputs, outputs);
+    }
+};
+
 WDsp *w_createDSPInstance(WFactory *factory, int sample_rate, int nvoices, bool group_voices)
 {
     // Whether the DSP voices should be controlled by faust from incoming MIDI
@@ -73,16 +738,67 @@ WDsp *w_createDSPInstance(WFactory *factory, int sample_rate, int nvoices, bool
         midiControlledVoices = false;
     }
 
+    // We do what factory->createPolyDSPInstance does, but with our own poly
+    // DSP class:
+    WPolyDsp *poly = new WPolyDsp(factory->fProcessFactory->createDSPInstance(), nvoices, midiControlledVoices, group_voices);
+    dsp_poly *poly_effect;
+    if (factory->fEffectFactory)
+    {
+        // The poly DSP has to be controlled with MIDI, so it is kept separated
+        // from the dsp_sequencer:
+        poly_effect = new dsp_poly_effect(poly, new dsp_sequencer(poly, factory->fEffectFactory->createDSPInstance()));
+    }
+    else
+    {
+        poly_effect = new dsp_poly_effect(poly, poly);
+    }
+
     // timed_dsp is needed for sample-accurate control (such as for MIDI clock).
     // See https://faustdoc.grame.fr/manual/architectures/#sample-accurate-control
-    WDsp *dsp = new timed_dsp(factory->createPolyDSPInstance(nvoices, midiControlledVoices, group_voices));
+    WDsp *dsp = new WTimedDsp(new ScheduledZonesDsp(poly_effect), midiControlledVoices ? poly : nullptr);
     dsp->init(sample_rate);
     return dsp;
 }
 
+WPoly *w_getPoly(WDsp *dsp)
+{
+    WTimedDsp *timed = dynamic_cast<WTimedDsp *>(dsp);
+    return timed ? timed->fPoly : nullptr;
+}
+
+WScheduledZones *w_getScheduledZones(WDsp *dsp)
+{
+    WTimedDsp *timed = dynamic_cast<WTimedDsp *>(dsp);
+    return timed ? timed->fScheduled : nullptr;
+}
+
+void w_setMpeConfig(WPoly *poly, const WMpeConfig *config)
+{
+    poly->fMpe = config != nullptr;
+    if (config)
+        poly->fMpeConfig = *config;
+    poly->resetChannels();
+}
+
+// Reads the `latency_samples` global metadata of a DSP
+struct LatencyMeta : public Meta
+{
+    int fLatency = 0;
+
+    void declare(const char *key, const char *value)
+    {
+        if (strcmp(key, "latency_samples") == 0)
+        {
+            fLatency = std::max(0, atoi(value));
+        }
+    }
+};
+
 DspInfo w_getDSPInfo(WDsp *dsp)
 {
-    return {dsp->getSampleRate(), dsp->getNumInputs(), dsp->getNumOutputs()};
+    LatencyMeta meta;
+    dsp->metadata(&meta);
+    return {dsp->getSampleRate(), dsp->getNumInputs(), dsp->getNumOutputs(), meta.fLatency};
 }
 
 void w_computeDSP(WDsp *dsp, int count, float **buf)
@@ -185,23 +901,89 @@ public:
     }
 };
 
+// A midi_handler that, in addition to dispatching incoming MIDI messages to
+// the MidiUI and poly DSP, collects the messages they send (eg. from bargraphs
+// with a [midi:ctrl N] metadata)
+class MidiOutputHandler : public midi_handler
+{
+private:
+    static const int kMaxMessages = 1024;
+
+    // A single-producer single-consumer ring: the messages are pushed on the
+    // audio thread, while the DSP computes, and may be popped from another
+    // one. One slot stays empty to tell a full ring from an empty one
+    WMidiMessage fMessages[kMaxMessages];
+    std::atomic<int> fWrite{0};
+    std::atomic<int> fRead{0};
+
+    // Messages that don't fit are dropped
+    void push(int status, int channel, int data1, int data2, int len)
+    {
+        int write = fWrite.load(std::memory_order_relaxed);
+        int next = (write + 1) % kMaxMessages;
+        if (next == fRead.load(std::memory_order_acquire))
+            return;
+        fMessages[write] = {
+            (double)fDate, {(unsigned char)(status | channel), (unsigned char)data1, (unsigned char)data2}, len};
+        fWrite.store(next, std::memory_order_release);
+    }
+
+public:
+    MapUI *keyOn(int channel, int pitch, int velocity)
+    {
+        push(MIDI_NOTE_ON, channel, pitch, velocity, 3);
+        return nullptr;
+    }
+    void keyOff(int channel, int pitch, int velocity) { push(MIDI_NOTE_OFF, channel, pitch, velocity, 3); }
+    void keyPress(int channel, int pitch, int press) { push(MIDI_POLY_AFTERTOUCH, channel, pitch, press, 3); }
+    void chanPress(int channel, int press) { push(MIDI_AFTERTOUCH, channel, press, 0, 2); }
+    void ctrlChange(int channel, int ctrl, int value) { push(MIDI_CONTROL_CHANGE, channel, ctrl, value, 3); }
+    void progChange(int channel, int pgm) { push(MIDI_PROGRAM_CHANGE, channel, pgm, 0, 2); }
+    void pitchWheel(int channel, int wheel) { push(MIDI_PITCH_BEND, channel, wheel & 0x7F, (wheel >> 7) & 0x7F, 3); }
+
+    // The sample of the current buffer the next pushed messages are dated at
+    int fDate = 0;
+
+    int pop(WMidiMessage *msgs, int max_msgs)
+    {
+        int read = fRead.load(std::memory_order_relaxed);
+        int write = fWrite.load(std::memory_order_acquire);
+        int n = 0;
+        for (; n < max_msgs && read != write; n++)
+        {
+            msgs[n] = fMessages[read];
+            read = (read + 1) % kMaxMessages;
+        }
+        fRead.store(read, std::memory_order_release);
+        return n;
+    }
+};
+
+// Longer SysEx messages are dropped
+static const size_t kMaxSysExLen = 4096;
+
 struct WUIs
 {
-    midi_handler *fMidiHandler;
+    MidiOutputHandler *fMidiHandler;
     MidiUI *fMidiUi;
     WidgetDeclGUI *fWidgetGui;
+    // Preallocated, so that SysEx messages can be handled on the audio thread
+    std::vector<unsigned char> fSysEx;
 };
 
 WUIs *w_createUIs(WDsp *dsp, void *gui_builder)
 {
     WUIs *uis = new WUIs();
-    uis->fMidiHandler = new midi_handler();
+    uis->fMidiHandler = new MidiOutputHandler();
     uis->fMidiUi = new MidiUI(uis->fMidiHandler);
     uis->fWidgetGui = new WidgetDeclGUI(gui_builder);
+    uis->fSysEx.reserve(kMaxSysExLen);
     dsp->buildUserInterface(uis->fMidiUi);
     dsp->buildUserInterface(uis->fWidgetGui);
     uis->fMidiUi->run();
     uis->fWidgetGui->run();
+    if (WScheduledZones *scheduled = w_getScheduledZones(dsp))
+        scheduled->fUis = uis;
     return uis;
 }
 
@@ -215,29 +997,136 @@ void w_deleteUIs(WUIs *uis)
     delete uis;
 }
 
-void w_updateAllGuis()
+static void flushMidiOutput(WUIs *uis, int date)
 {
-    GUI::updateAllGuis();
+    uis->fMidiHandler->fDate = date;
+    // Only this DSP's MidiUI: GUI::updateAllGuis would update the GUIs of all
+    // the DSPs of the process
+    uis->fMidiUi->updateAllZones();
 }
 
-void w_handleRawMidi(WUIs *uis, double time, const unsigned char bytes[3])
+void w_handleRawMidi(WUIs *uis, double time, const unsigned char *bytes, int len)
 {
+    if (bytes[0] == midi::MIDI_SYSEX_START)
+    {
+        if ((size_t)len > kMaxSysExLen)
+            return;
+        uis->fSysEx.assign(bytes, bytes + len);
+        uis->fMidiHandler->handleMessage(time, midi::MIDI_SYSEX_START, uis->fSysEx);
+        return;
+    }
+
+    unsigned char data1 = len > 1 ? bytes[1] : 0;
+    unsigned char data2 = len > 2 ? bytes[2] : 0;
+
+    // System messages (clock, transport, song position...) carry no channel:
+    if (bytes[0] >= 0xF0)
+    {
+        if (bytes[0] == midi::MIDI_CLOCK || bytes[0] == midi::MIDI_START ||
+            bytes[0] == midi::MIDI_CONT || bytes[0] == midi::MIDI_STOP)
+            w_handleMidiSync(uis, time, (WMidiSyncMsg)bytes[0]);
+        else
+            uis->fMidiHandler->handleData2(time, bytes[0], 0, data1, data2);
+        return;
+    }
+
     // Faust expects status (type) bits _not_ to be shifted, so
     // we leave status bits in place and just set the other ones
     // to zero:
     uint8_t type = bytes[0] & 0b11110000;
     uint8_t channel = bytes[0] & 0b00001111;
 
-    if (type == midi::MIDI_CLOCK || type == midi::MIDI_START ||
-        type == midi::MIDI_CONT || type == midi::MIDI_STOP)
-        uis->fMidiHandler->handleSync(time, type);
-    else if (type == midi::MIDI_PROGRAM_CHANGE || type == midi::MIDI_AFTERTOUCH)
-        uis->fMidiHandler->handleData1(time, type, channel, bytes[1]);
+    if (type == midi::MIDI_PROGRAM_CHANGE || type == midi::MIDI_AFTERTOUCH)
+        uis->fMidiHandler->handleData1(time, type, channel, data1);
     else
-        uis->fMidiHandler->handleData2(time, type, channel, bytes[1], bytes[2]);
+        uis->fMidiHandler->handleData2(time, type, channel, data1, data2);
 }
 
 void w_handleMidiSync(WUIs *uis, double time, WMidiSyncMsg status)
 {
+    // Faust only distinguishes between running and stopped, so a continue is
+    // just another start from its point of view:
+    if (status == MIDI_CONT)
+        status = MIDI_START;
     uis->fMidiHandler->handleSync(time, status);
 }
+
+bool w_scheduleZoneChange(WScheduledZones *scheduled, float *zone, double time, float value)
+{
+    return scheduled->schedule(zone, time, value);
+}
+
+int w_popMidiOutput(WUIs *uis, WMidiMessage *msgs, int max_msgs)
+{
+    return uis->fMidiHandler->pop(msgs, max_msgs);
+}
+
+void w_setVoicePolicy(WPoly *poly, WVoicePolicy policy)
+{
+    poly->fPolicy = policy;
+    poly->fNumHeld = 0;
+}
+
+int w_getVoiceStates(WPoly *poly, WVoiceState *states, int max_voices)
+{
+    int n = std::min(max_voices, (int)poly->fVoiceInfos.size());
+    int64_t now = poly->fSampleCount;
+    for (int i = 0; i < n; i++)
+    {
+        WPolyDsp::VoiceInfo &info = poly->fVoiceInfos[i];
+        states[i] = {(WVoiceActivity)info.fPublishedActivity.load(),
+                     info.fPublishedPitch,
+                     info.fPublishedVelocity,
+                     now - info.fPublishedStartSample};
+    }
+    return poly->fVoiceInfos.size();
+}
+
+void w_setTuning(WPoly *poly, const double *freqs)
+{
+    poly->setTuning(freqs);
+}
+
+void w_setNextNoteId(WPoly *poly, int note_id)
+{
+    poly->fNextNoteId = note_id;
+}
+
+void w_setCutOnRelease(WPoly *poly, bool cut)
+{
+    poly->fCutOnRelease = cut;
+}
+
+void w_setNextNoteOffId(WPoly *poly, int note_id)
+{
+    poly->fNextNoteOffId = note_id;
+}
+
+bool w_playsNote(WPoly *poly, int voice, WNoteRef note)
+{
+    return voice >= 0 && voice < (int)poly->fVoiceTable.size() &&
+           poly->playsNote(voice, note.note_id, note.channel, note.pitch);
+}
+
+void w_setNoteExpression(WPoly *poly, WNoteRef note, WNoteExpression expr, float value)
+{
+    for (int i = 0; i < (int)poly->fVoiceTable.size(); i++)
+    {
+        if (poly->playsNote(i, note.note_id, note.channel, note.pitch))
+            poly->setExpression(i, expr, value);
+    }
+}
+
+void w_allNotesOff(WPoly *poly, bool hard)
+{
+    poly->allNotesOff(hard);
+}
+
+int w_popTerminatedNotes(WPoly *poly, WNoteRef *notes, int max_notes)
+{
+    int n = std::min(max_notes, poly->fNumTerminated);
+    std::copy(poly->fTerminated, poly->fTerminated + n, notes);
+    std::copy(poly->fTerminated + n, poly->fTerminated + poly->fNumTerminated, poly->fTerminated);
+    poly->fNumTerminated -= n;
+    return n;
+}
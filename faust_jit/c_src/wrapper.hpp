@@ -8,9 +8,13 @@
 
 struct dsp_poly_factory;
 struct dsp;
+struct WPolyDsp;
+struct ScheduledZonesDsp;
 
 typedef dsp_poly_factory WFactory;
 typedef dsp WDsp;
+typedef WPolyDsp WPoly;
+typedef ScheduledZonesDsp WScheduledZones;
 
 WFactory *w_createDSPFactoryFromFile(const char *filepath, const int argc, const char *argv[], char *err_msg_c);
 
@@ -37,8 +41,135 @@ void w_deleteDSPFactory(WFactory *factory);
 //   sent. This is _not_ an intended feature of the plugin, just a consequence
 //   of how Faust handles polyphony.
 //
+// If `group_voices` is false, the UI of an instrument with several voices will
+// contain the widgets of each voice, in addition to those controlling all the
+// voices at once.
+//
 WDsp *w_createDSPInstance(WFactory *factory, int sample_rate, int nvoices, bool group_voices);
 
+// Returns the polyphonic part of a DSP created by w_createDSPInstance, or null
+// if that DSP is not an instrument
+WPoly *w_getPoly(WDsp *dsp);
+
+// Returns the part of a DSP created by w_createDSPInstance that applies the
+// changes queued with w_scheduleZoneChange
+WScheduledZones *w_getScheduledZones(WDsp *dsp);
+
+// How voices are allocated to new notes
+enum WVoicePolicy
+{
+    // When all voices are used, steal a voice (preferably a released one)...
+    STEAL_OLDEST = 0, // ...that started first (Faust's default)
+    STEAL_QUIETEST,   // ...that is the quietest
+    STEAL_LOWEST,     // ...that plays the lowest note
+    STEAL_HIGHEST,    // ...that plays the highest note
+    // Only one voice plays. Releasing a note goes back to the last note still
+    // held (if any). A new note...
+    MONO_LEGATO,    // ...only changes the pitch of the voice if it was playing
+    MONO_RETRIGGER, // ...always restarts the voice
+};
+
+void w_setVoicePolicy(WPoly *poly, WVoicePolicy policy);
+
+// If `cut`, released notes silence and free their voice immediately instead
+// of letting it play its release
+void w_setCutOnRelease(WPoly *poly, bool cut);
+
+// How MIDI channels are used by an MPE controller. The zone's master channel
+// is 0 for the lower zone (with member channels 1 to member_channels) and 15
+// for the upper zone (with member channels 15-member_channels to 14)
+struct WMpeConfig
+{
+    bool lower_zone;
+    int member_channels;
+    // In semitones
+    float pitch_bend_range;
+    float master_pitch_bend_range;
+};
+
+// Makes pitch bend, channel pressure and CC74 (timbre) received on member
+// channels apply only to the voices playing the notes of these channels (to
+// their freq widgets and to widgets with an [mpe:pressure] or [mpe:timbre]
+// metadata). A null config disables MPE. Must not be called while the DSP
+// receives MIDI
+void w_setMpeConfig(WPoly *poly, const WMpeConfig *config);
+
+// Sets the frequency given to the voices for each MIDI note. `freqs` must
+// contain 128 frequencies (in Hz), or be null to go back to 12-TET. Notes that
+// are already playing are not retuned. Can be called from any thread
+void w_setTuning(WPoly *poly, const double *freqs);
+
+// Identifies the voice(s) playing a note. A negative note_id means "any note
+// id", in which case the voices are identified by their channel and pitch
+struct WNoteRef
+{
+    int note_id;
+    int channel;
+    int pitch;
+};
+
+// Gives an id to the next note that will be started by a MIDI note on
+void w_setNextNoteId(WPoly *poly, int note_id);
+
+// Makes the next MIDI note off release only the voice whose note has this id
+// (-1 to release the voice playing the same pitch, as usual)
+void w_setNextNoteOffId(WPoly *poly, int note_id);
+
+// Whether the voice of index `voice` (in the order of the voices' widgets)
+// plays or releases a note
+bool w_playsNote(WPoly *poly, int voice, WNoteRef note);
+
+// Changes of timbre of a note while it is playing
+enum WNoteExpression
+{
+    // These go to the widgets with an [mpe:pressure], [mpe:timbre],
+    // [mpe:pan], [mpe:vibrato] or [mpe:expression] metadata. The value is
+    // between 0 and 1, and mapped to the range of the widget
+    NOTE_PRESSURE = 0,
+    NOTE_TIMBRE,
+    NOTE_PAN,
+    NOTE_VIBRATO,
+    NOTE_EXPRESSION,
+    // Multiplies the gain given by the note velocity (1 = unchanged)
+    NOTE_VOLUME,
+    // Offsets the frequency of the note, in semitones
+    NOTE_TUNING,
+};
+
+void w_setNoteExpression(WPoly *poly, WNoteRef note, WNoteExpression expr, float value);
+
+enum WVoiceActivity
+{
+    VOICE_FREE = 0,
+    VOICE_PLAYING,
+    VOICE_RELEASING,
+};
+
+// What a voice was doing at the end of the last computed buffer
+struct WVoiceState
+{
+    WVoiceActivity activity;
+    // The note the voice plays, or last played if it is free
+    int pitch;
+    int velocity;
+    // How many samples ago the note started
+    long long age;
+};
+
+// Writes to `states` the state of (at most `max_voices` of) the voices. Returns
+// the total number of voices. Can be called from any thread, without locking
+int w_getVoiceStates(WPoly *poly, WVoiceState *states, int max_voices);
+
+// Releases all the voices (like a MIDI All Notes Off), or if `hard` silences
+// them immediately (like a MIDI All Sound Off). Must not be called while the
+// DSP computes
+void w_allNotesOff(WPoly *poly, bool hard);
+
+// Moves to `notes` (at most `max_notes` of) the notes whose voice has
+// terminated (ie. finished its release or been stolen) since the last call.
+// Returns the number of notes written
+int w_popTerminatedNotes(WPoly *poly, WNoteRef *notes, int max_notes);
+
 /* Information about the currently loaded DSP
  */
 struct DspInfo
@@ -46,6 +177,9 @@ struct DspInfo
     int sample_rate;
     int num_inputs;
     int num_outputs;
+    // The delay (in samples) that the DSP adds to the signal, as given by the
+    // `declare latency_samples "N";` metadata of the script (0 if absent)
+    int latency;
 };
 
 DspInfo w_getDSPInfo(WDsp *dsp);
@@ -85,11 +219,12 @@ struct WUIs;
 
 WUIs *w_createUIs(WDsp *dsp, void *gui_builder);
 
+// Must be called after the DSP is deleted
 void w_deleteUIs(WUIs *h);
 
-void w_updateAllGuis();
-
-void w_handleRawMidi(WUIs *h, double time, const unsigned char bytes[3]);
+// `bytes` must contain exactly one complete message (with its status byte),
+// which can be a SysEx (of up to 4096 bytes, longer ones are dropped)
+void w_handleRawMidi(WUIs *h, double time, const unsigned char *bytes, int len);
 
 // Taken from Faust
 enum WMidiSyncMsg
@@ -102,4 +237,26 @@ enum WMidiSyncMsg
 
 void w_handleMidiSync(WUIs *h, double time, WMidiSyncMsg status);
 
+// A MIDI message emitted by the DSP (SysEx messages are not supported)
+struct WMidiMessage
+{
+    // The last sample of the slice of the buffer at the end of which the
+    // message was sent
+    double time;
+    unsigned char bytes[3];
+    int len;
+};
+
+// Moves to `msgs` (at most `max_msgs` of) the MIDI messages emitted by the DSP
+// since the last call, in chronological order. Returns the number of messages
+// written. Can be called from another thread than the one computing the DSP,
+// but only one thread at a time
+int w_popMidiOutput(WUIs *h, WMidiMessage *msgs, int max_msgs);
+
+// Queues a change of value for some zone, which will be applied at sample
+// `time` of the next computed buffer (or of a later one, if `time` is beyond
+// the end of that buffer). Returns false if the zone is not an input zone of
+// the DSP, or if its queue is full
+bool w_scheduleZoneChange(WScheduledZones *scheduled, float *zone, double time, float value);
+
 #endif
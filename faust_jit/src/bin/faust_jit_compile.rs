@@ -0,0 +1,28 @@
+//! The helper process run by [`faust_jit::CompileHelper`]:
+//!
+//! `faust_jit_compile <dest_folder> <script_path> [import_path...]`
+//!
+//! Compiles the script and writes its factory to the destination folder. Exits
+//! with an error, and the Faust error message on stderr, if the script doesn't
+//! compile
+
+use std::{
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+fn main() -> ExitCode {
+    let args: Vec<PathBuf> = std::env::args_os().skip(1).map(PathBuf::from).collect();
+    let [dest_folder, script_path, import_paths @ ..] = &args[..] else {
+        eprintln!("Usage: faust_jit_compile <dest_folder> <script_path> [import_path...]");
+        return ExitCode::FAILURE;
+    };
+    let import_paths: Vec<&Path> = import_paths.iter().map(PathBuf::as_path).collect();
+    match faust_jit::compile_to_folder(script_path, &import_paths, dest_folder) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(msg) => {
+            eprint!("{}", msg);
+            ExitCode::FAILURE
+        }
+    }
+}
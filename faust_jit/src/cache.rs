@@ -1,5 +1,6 @@
 use chksum_sha1 as sha1;
 use sha1::{Chksumable, SHA1};
+use std::convert::Infallible;
 use std::fs;
 use std::path::{Path, PathBuf};
 
@@ -76,8 +77,23 @@ pub struct CacheWriter {
 }
 
 impl CacheWriter {
+    /// Where the result is once it has been written
+    pub fn final_folder(&self) -> &Path {
+        &self.final_dir
+    }
+
     /// Get the folder in the cache in which to write the result
     pub fn with_dest_folder<T>(&self, f: impl FnOnce(&Path) -> T) -> T {
+        self.try_with_dest_folder(|folder| Ok::<_, Infallible>(f(folder)))
+            .unwrap_or_else(|e| match e {})
+    }
+
+    /// Like [`Self::with_dest_folder`], but the folder is discarded instead of
+    /// being stored in the cache if `f` fails
+    pub fn try_with_dest_folder<T, E>(
+        &self,
+        f: impl FnOnce(&Path) -> Result<T, E>,
+    ) -> Result<T, E> {
         fs::create_dir_all(&self.temp_dir).expect(&format!(
             "Cache temp folder {:?} couldn't be created",
             &self.temp_dir
@@ -85,9 +101,9 @@ impl CacheWriter {
 
         let result = f(&self.temp_dir);
 
-        if self.final_dir.exists() {
-            // Result has been created elsewhere in the meatime, we just
-            // remove the temp dir:
+        if result.is_err() || self.final_dir.exists() {
+            // Result couldn't be computed, or has been created elsewhere in
+            // the meatime, we just remove the temp dir:
             fs::remove_dir_all(&self.temp_dir)
                 .expect(&format!("Couldn't remove temp dir {:?}", self.temp_dir));
         } else {
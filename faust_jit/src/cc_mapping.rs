@@ -0,0 +1,68 @@
+use crate::ZonePtr;
+
+/// Makes a MIDI controller drive a widget of the DSP, in addition to those
+/// given by the `[midi:ctrl N]` metadata of the script. Mappings are created at
+/// runtime (e.g. by MIDI learn), see [`crate::SingletonDsp::set_cc_mappings`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct CcMapping {
+    /// None to respond to the controller on any channel
+    pub channel: Option<u8>,
+    pub cc: u8,
+    /// The full path of the widget (see [`crate::ParamRef::Path`])
+    pub path: String,
+    /// The values given to the widget when the controller is at 0 and at 127.
+    /// `min` can be greater than `max`, to invert the controller
+    pub min: f32,
+    pub max: f32,
+    /// The value of the controller (between 0 and 1) is raised to this power
+    /// before being mapped to the range. 1 is linear
+    pub exponent: f32,
+}
+
+impl CcMapping {
+    /// Maps the controller to the whole range of a widget, linearly
+    pub fn new(channel: Option<u8>, cc: u8, path: &str, min: f32, max: f32) -> Self {
+        Self {
+            channel,
+            cc,
+            path: path.to_owned(),
+            min,
+            max,
+            exponent: 1.0,
+        }
+    }
+
+    fn responds_to(&self, channel: u8, cc: u8) -> bool {
+        self.cc == cc && self.channel.is_none_or(|c| c == channel)
+    }
+
+    fn value(&self, cc_value: u8) -> f32 {
+        let t = (cc_value as f32 / 127.0).powf(self.exponent.max(0.0));
+        self.min + (self.max - self.min) * t
+    }
+}
+
+/// The mappings whose widget was found in the DSP, along with the zone of that
+/// widget
+#[derive(Debug, Default)]
+pub(crate) struct CcMappings {
+    pub(crate) active: Vec<(CcMapping, ZonePtr)>,
+}
+
+impl CcMappings {
+    /// Calls `f` on the zone of each widget driven by the given controller, with
+    /// its new value. Does not allocate
+    pub(crate) fn control_change(
+        &self,
+        channel: u8,
+        cc: u8,
+        cc_value: u8,
+        mut f: impl FnMut(&ZonePtr, f32),
+    ) {
+        for (mapping, zone) in &self.active {
+            if mapping.responds_to(channel, cc) {
+                f(zone, mapping.value(cc_value));
+            }
+        }
+    }
+}
@@ -0,0 +1,99 @@
+//! Compiling scripts in a separate process (the `faust_jit_compile` binary of
+//! this crate), so that a crash or an infinite loop of libfaust or LLVM while
+//! compiling doesn't take the calling process down with it.
+
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use crate::{faust_error, new_factory_from_file, path_to_cstring, wrapper::*};
+
+/// How often the helper process is checked for completion
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Where to find the `faust_jit_compile` executable, and how long to let it
+/// compile a script before killing it
+#[derive(Debug, Clone)]
+pub struct CompileHelper {
+    pub exe: PathBuf,
+    pub timeout: Duration,
+}
+
+impl CompileHelper {
+    /// Runs the helper, which writes the factory of the script to
+    /// `dest_folder` (see [`compile_to_folder`])
+    pub(crate) fn compile(
+        &self,
+        script_path: &Path,
+        import_paths: &[&Path],
+        dest_folder: &Path,
+    ) -> Result<(), String> {
+        let mut child = Command::new(&self.exe)
+            .arg(dest_folder)
+            .arg(script_path)
+            .args(import_paths)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Could not start compile helper {:?}: {}", self.exe, e))?;
+        // Read while the helper runs, as it would block once the pipe is full:
+        let stderr = child.stderr.take();
+        let stderr_reader = std::thread::spawn(move || {
+            let mut error_msg = String::new();
+            if let Some(mut stderr) = stderr {
+                let _ = stderr.read_to_string(&mut error_msg);
+            }
+            error_msg
+        });
+        let started = Instant::now();
+        let status = loop {
+            if let Some(status) = child.try_wait().map_err(|e| e.to_string())? {
+                break status;
+            }
+            if started.elapsed() > self.timeout {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!(
+                    "Compilation was stopped after {:.1}s",
+                    self.timeout.as_secs_f32()
+                ));
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        };
+        // The pipe is closed once the helper has exited:
+        let error_msg = stderr_reader.join().unwrap_or_default();
+        if status.success() {
+            return Ok(());
+        }
+        if error_msg.is_empty() {
+            Err(format!("Compile helper failed ({})", status))
+        } else {
+            Err(error_msg)
+        }
+    }
+}
+
+/// Compiles a script and writes its factory to a folder, from which
+/// [`crate::SingletonDsp`] can then read it. This is what the helper process
+/// runs
+pub fn compile_to_folder(
+    script_path: &Path,
+    import_paths: &[&Path],
+    dest_folder: &Path,
+) -> Result<(), String> {
+    let dest_folder = path_to_cstring(dest_folder)?;
+    let mut error_msg_buf = [0; 4096];
+    let fac_ptr = new_factory_from_file(script_path, import_paths, &mut error_msg_buf)?;
+    if fac_ptr.is_null() {
+        return Err(faust_error(&error_msg_buf));
+    }
+    unsafe {
+        w_writeFactoryToFolder(fac_ptr, dest_folder.as_ptr());
+        w_deleteDSPFactory(fac_ptr);
+    }
+    Ok(())
+}
@@ -0,0 +1,404 @@
+//! A process-wide queue serializing the calls to libfaust's compiler, which is
+//! not reentrant. Several [`crate::SingletonDsp`]s may be loaded at the same
+//! time from different threads (e.g. by several plugin instances when a project
+//! is opened), but only one of them compiles at a time.
+
+use std::{
+    any::Any,
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+};
+
+/// Identifies who sends compile requests. A request supersedes the pending
+/// (ie. not yet started) request of the same requester, if there is one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompileRequester(u64);
+
+impl CompileRequester {
+    /// A new requester, different from all the ones created before
+    pub fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        Self(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl Default for CompileRequester {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Where the result of a request is put, for all its waiters to clone it
+type ResultSlot<T> = Mutex<Option<T>>;
+
+struct Request {
+    /// Requests with the same key compile the same thing
+    key: String,
+    /// The calls waiting for the result (identified by a unique id), with
+    /// their requester
+    waiters: Vec<(u64, CompileRequester)>,
+    /// A [`ResultSlot`], whose type depends on the request
+    result: Arc<dyn Any + Send + Sync>,
+    /// Set once one of the waiters runs the request
+    started: bool,
+}
+
+/// A call to libfaust that must not run during a compilation, and that nobody
+/// waits for
+type DeferredJob = Box<dyn FnOnce() + Send>;
+
+struct QueueState {
+    next_id: u64,
+    /// Only the first request can be started
+    requests: VecDeque<Request>,
+    /// Run as soon as no request is running, before the next one starts
+    deferred: Vec<DeferredJob>,
+    /// Whether deferred jobs are running, in which case no request can start
+    running_deferred: bool,
+}
+
+impl QueueState {
+    fn index_of(&self, waiter: u64) -> Option<usize> {
+        self.requests
+            .iter()
+            .position(|req| req.waiters.iter().any(|(id, _)| *id == waiter))
+    }
+}
+
+/// See the module doc
+pub struct CompileQueue {
+    state: Mutex<QueueState>,
+    changed: Condvar,
+}
+
+static GLOBAL_QUEUE: CompileQueue = CompileQueue::new();
+
+/// Removes the running request once it is done. If it panicked instead, lets
+/// another of its waiters run it again
+struct RunningGuard<'a> {
+    queue: &'a CompileQueue,
+    waiter: u64,
+    done: bool,
+}
+
+impl Drop for RunningGuard<'_> {
+    fn drop(&mut self) {
+        let mut state = self.queue.lock();
+        if let Some(request) = state.requests.front_mut() {
+            request.waiters.retain(|(id, _)| *id != self.waiter);
+            request.started = false;
+            if self.done || request.waiters.is_empty() {
+                state.requests.pop_front();
+            }
+        }
+        self.queue.run_deferred(state);
+        self.queue.changed.notify_all();
+    }
+}
+
+/// Lets the requests start again once the deferred jobs have run, even if one
+/// of them panicked
+struct DeferredGuard<'a>(&'a CompileQueue);
+
+impl Drop for DeferredGuard<'_> {
+    fn drop(&mut self) {
+        self.0.lock().running_deferred = false;
+        self.0.changed.notify_all();
+    }
+}
+
+impl CompileQueue {
+    const fn new() -> Self {
+        Self {
+            state: Mutex::new(QueueState {
+                next_id: 0,
+                requests: VecDeque::new(),
+                deferred: Vec::new(),
+                running_deferred: false,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// The queue shared by the whole process
+    pub fn global() -> &'static Self {
+        &GLOBAL_QUEUE
+    }
+
+    fn lock(&self) -> MutexGuard<'_, QueueState> {
+        // A panic while the lock is taken cannot leave the queue inconsistent:
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Waits for its turn and runs `compile`. Returns `None` (without running
+    /// it) if another request of the same requester superseded this one in the
+    /// meantime.
+    ///
+    /// Identical requests (with the same `key`) are deduplicated: a new request
+    /// identical to one that is still pending joins it instead of being queued,
+    /// so `compile` runs only once for both and they share its result.
+    ///
+    /// `on_position` is called each time the number of requests that will run
+    /// before this one changes, and with 0 once it runs. It is never called
+    /// with the queue locked, so it may take as long as it needs.
+    pub fn run<T: Clone + Send + 'static>(
+        &self,
+        requester: CompileRequester,
+        key: &str,
+        mut on_position: impl FnMut(usize),
+        compile: impl FnOnce() -> T,
+    ) -> Option<T> {
+        let mut state = self.lock();
+        let waiter = state.next_id;
+        state.next_id += 1;
+        for request in state.requests.iter_mut().filter(|req| !req.started) {
+            request.waiters.retain(|(_, r)| *r != requester);
+        }
+        state
+            .requests
+            .retain(|req| req.started || !req.waiters.is_empty());
+        let joined = state
+            .requests
+            .iter_mut()
+            .find(|req| !req.started && req.key == key && req.result.is::<ResultSlot<T>>());
+        let result = match joined {
+            Some(request) => {
+                request.waiters.push((waiter, requester));
+                Arc::clone(&request.result)
+            }
+            None => {
+                let result: Arc<dyn Any + Send + Sync> = Arc::new(ResultSlot::<T>::new(None));
+                state.requests.push_back(Request {
+                    key: key.to_string(),
+                    waiters: vec![(waiter, requester)],
+                    result: Arc::clone(&result),
+                    started: false,
+                });
+                result
+            }
+        };
+        let result = result
+            .downcast::<ResultSlot<T>>()
+            .unwrap_or_else(|_| unreachable!("Only requests with the same result type are joined"));
+        self.changed.notify_all();
+
+        let mut last_position = None;
+        loop {
+            if let Some(value) = &*result.lock().unwrap_or_else(|e| e.into_inner()) {
+                return Some(value.clone());
+            }
+            let index = state.index_of(waiter)?;
+            if last_position != Some(index) {
+                last_position = Some(index);
+                drop(state);
+                on_position(index);
+                state = self.lock();
+                continue;
+            }
+            if index == 0 && !state.requests[0].started && !state.running_deferred {
+                state.requests[0].started = true;
+                drop(state);
+                let mut guard = RunningGuard {
+                    queue: self,
+                    waiter,
+                    done: false,
+                };
+                let value = compile();
+                *result.lock().unwrap_or_else(|e| e.into_inner()) = Some(value.clone());
+                guard.done = true;
+                return Some(value);
+            }
+            state = self.changed.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    /// Runs `job` once no request is running, before the pending requests,
+    /// without waiting for it. Right away on this thread if the queue is idle,
+    /// else on the thread of the running request once it is done. For the
+    /// short calls to libfaust that must not run during a compilation, like
+    /// deleting a factory
+    pub fn defer(&self, job: impl FnOnce() + Send + 'static) {
+        let mut state = self.lock();
+        state.deferred.push(Box::new(job));
+        self.run_deferred(state);
+    }
+
+    /// Runs the deferred jobs (and the ones deferred meanwhile), unless a
+    /// request or other deferred jobs are running
+    fn run_deferred<'a>(&'a self, mut state: MutexGuard<'a, QueueState>) {
+        while !state.deferred.is_empty()
+            && !state.running_deferred
+            && !state.requests.iter().any(|req| req.started)
+        {
+            let jobs = std::mem::take(&mut state.deferred);
+            state.running_deferred = true;
+            drop(state);
+            let guard = DeferredGuard(self);
+            for job in jobs {
+                job();
+            }
+            drop(guard);
+            state = self.lock();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::mpsc::{channel, Receiver, Sender},
+        thread::{self, JoinHandle},
+    };
+
+    /// Sends the positions of a request, and returns what it returned
+    fn spawn_request(
+        queue: &'static CompileQueue,
+        requester: CompileRequester,
+        key: &'static str,
+        value: u32,
+        compiled: Sender<u32>,
+    ) -> (JoinHandle<Option<u32>>, Receiver<usize>) {
+        let (positions_tx, positions) = channel();
+        let handle = thread::spawn(move || {
+            queue.run(
+                requester,
+                key,
+                |position| positions_tx.send(position).unwrap(),
+                || {
+                    compiled.send(value).unwrap();
+                    value
+                },
+            )
+        });
+        (handle, positions)
+    }
+
+    /// Runs a request that keeps the queue busy until the returned sender is
+    /// dropped
+    fn block(queue: &'static CompileQueue) -> (JoinHandle<()>, Sender<()>) {
+        let (unblock, blocked) = channel::<()>();
+        let (started_tx, started) = channel();
+        let handle = thread::spawn(move || {
+            queue.run(
+                CompileRequester::new(),
+                "block",
+                |_| {},
+                || {
+                    started_tx.send(()).unwrap();
+                    let _ = blocked.recv();
+                },
+            );
+        });
+        started.recv().unwrap();
+        (handle, unblock)
+    }
+
+    fn new_queue() -> &'static CompileQueue {
+        Box::leak(Box::new(CompileQueue::new()))
+    }
+
+    #[test]
+    fn runs_requests_in_order_and_tells_their_positions() {
+        let queue = new_queue();
+        let (blocker, unblock) = block(queue);
+        let (compiled_tx, compiled) = channel();
+        let mut requests = vec![];
+        for (i, key) in ["a", "b", "c"].into_iter().enumerate() {
+            let (handle, positions) = spawn_request(
+                queue,
+                CompileRequester::new(),
+                key,
+                i as u32,
+                compiled_tx.clone(),
+            );
+            // Waiting for the request to be queued before sending the next one:
+            assert_eq!(positions.recv().unwrap(), i + 1);
+            requests.push((handle, positions));
+        }
+        drop(unblock);
+        blocker.join().unwrap();
+        for (i, (handle, positions)) in requests.into_iter().enumerate() {
+            assert_eq!(handle.join().unwrap(), Some(i as u32));
+            let rest: Vec<usize> = positions.iter().collect();
+            assert_eq!(rest.last(), Some(&0));
+            assert!(rest.windows(2).all(|w| w[0] > w[1]));
+        }
+        drop(compiled_tx);
+        assert_eq!(compiled.iter().collect::<Vec<_>>(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn a_new_request_supersedes_the_pending_one_of_its_requester() {
+        let queue = new_queue();
+        let (blocker, unblock) = block(queue);
+        let (compiled_tx, compiled) = channel();
+        let requester = CompileRequester::new();
+        let (first, first_positions) = spawn_request(queue, requester, "a", 1, compiled_tx.clone());
+        assert_eq!(first_positions.recv().unwrap(), 1);
+        let (second, second_positions) =
+            spawn_request(queue, requester, "b", 2, compiled_tx.clone());
+        assert_eq!(second_positions.recv().unwrap(), 1);
+        assert_eq!(first.join().unwrap(), None);
+        drop(unblock);
+        blocker.join().unwrap();
+        assert_eq!(second.join().unwrap(), Some(2));
+        drop(compiled_tx);
+        assert_eq!(compiled.iter().collect::<Vec<_>>(), vec![2]);
+    }
+
+    #[test]
+    fn identical_requests_share_one_result() {
+        let queue = new_queue();
+        let (blocker, unblock) = block(queue);
+        let (compiled_tx, compiled) = channel();
+        let (first, first_positions) =
+            spawn_request(queue, CompileRequester::new(), "a", 1, compiled_tx.clone());
+        assert_eq!(first_positions.recv().unwrap(), 1);
+        let (other, other_positions) =
+            spawn_request(queue, CompileRequester::new(), "b", 2, compiled_tx.clone());
+        assert_eq!(other_positions.recv().unwrap(), 2);
+        let (second, second_positions) =
+            spawn_request(queue, CompileRequester::new(), "a", 3, compiled_tx.clone());
+        // Joined the first request instead of being queued after the other one:
+        assert_eq!(second_positions.recv().unwrap(), 1);
+        drop(unblock);
+        blocker.join().unwrap();
+        // Either of them may run the request:
+        let shared = first.join().unwrap();
+        assert!(shared == Some(1) || shared == Some(3));
+        assert_eq!(second.join().unwrap(), shared);
+        assert_eq!(other.join().unwrap(), Some(2));
+        drop(compiled_tx);
+        assert_eq!(
+            compiled.iter().collect::<Vec<_>>(),
+            vec![shared.unwrap(), 2]
+        );
+    }
+
+    #[test]
+    fn deferred_jobs_run_between_requests_without_being_waited_for() {
+        let queue = new_queue();
+        let (done_tx, done) = channel();
+        // Right away when the queue is idle:
+        let idle_tx = done_tx.clone();
+        queue.defer(move || idle_tx.send(0).unwrap());
+        assert_eq!(done.try_recv(), Ok(0));
+
+        let (blocker, unblock) = block(queue);
+        let (pending, positions) =
+            spawn_request(queue, CompileRequester::new(), "a", 2, done_tx.clone());
+        assert_eq!(positions.recv().unwrap(), 1);
+        let deferred_tx = done_tx.clone();
+        queue.defer(move || deferred_tx.send(1).unwrap());
+        assert!(done.try_recv().is_err());
+        drop(unblock);
+        blocker.join().unwrap();
+        assert_eq!(pending.join().unwrap(), Some(2));
+        drop(done_tx);
+        assert_eq!(done.iter().collect::<Vec<_>>(), vec![1, 2]);
+    }
+}
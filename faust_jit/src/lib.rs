@@ -17,21 +17,42 @@
 use std::{
     cell::RefCell,
     ffi::{c_char, c_void, CStr, CString},
-    path::Path,
+    path::{Path, PathBuf},
     ptr::null_mut,
     sync::{
-        atomic::{AtomicBool, AtomicPtr, Ordering},
-        Mutex, RwLock,
+        atomic::{AtomicI32, AtomicPtr, Ordering},
+        Arc, Mutex, MutexGuard, RwLock,
     },
 };
 
 use wrapper::*;
 
 pub use cache::*;
+pub use cc_mapping::CcMapping;
+pub use compile_helper::{compile_to_folder, CompileHelper};
+pub use compile_queue::*;
+pub use midi::midi_message_len;
+pub use midi_clock::ClockData;
+pub use midi_transform::*;
+pub use poly::*;
+pub use transport::*;
+pub use tuning::Tuning;
 pub use widgets::*;
 pub use wrapper::DspInfo;
 
+use midi::*;
+use midi_clock::*;
+
 mod cache;
+mod cc_mapping;
+mod compile_helper;
+mod compile_queue;
+mod midi;
+mod midi_clock;
+mod midi_transform;
+mod poly;
+mod transport;
+mod tuning;
 mod widgets;
 mod wrapper;
 
@@ -54,14 +75,40 @@ impl std::fmt::Debug for ChanPtrs {
     }
 }
 
+/// Where the factory of a [`SingletonDsp`] comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FactorySource {
+    /// Compiled from the script, without cache
+    Compiled,
+    /// Compiled from the script, as it wasn't in the cache yet
+    CacheMiss,
+    /// Read from the cache, without compiling the script
+    CacheHit,
+    /// Compiled by a [`CompileHelper`] process, as it wasn't in the cache yet
+    Helper,
+    /// Given by the caller (see [`SingletonDsp::from_poly_factory_ptr`] and
+    /// [`SingletonDsp::from_dsp_ptr`])
+    External,
+}
+
 /// How to load a DSP
 pub enum DspLoadMode {
     /// Use the script metadata
     AutoDetect,
     /// Monophonic (always alive) effect
     Effect,
-    /// Polyphonic instrument with max number of voices
-    Instrument { nvoices: i32 },
+    /// Polyphonic instrument with max number of voices, optionally driven by
+    /// an MPE controller
+    ///
+    /// If `group_voices` is false, the widgets of each voice are also given
+    /// (see [`PolyWidgets`]), so that voices can be controlled individually
+    Instrument {
+        nvoices: i32,
+        voice_policy: VoicePolicy,
+        release: VoiceRelease,
+        group_voices: bool,
+        mpe: Option<MpeConfig>,
+    },
 }
 
 impl DspLoadMode {
@@ -70,38 +117,88 @@ impl DspLoadMode {
         match nvoices {
             -1 => Self::AutoDetect,
             0 => Self::Effect,
-            _ => Self::Instrument { nvoices },
+            _ => Self::Instrument {
+                nvoices,
+                voice_policy: VoicePolicy::default(),
+                release: VoiceRelease::default(),
+                group_voices: false,
+                mpe: None,
+            },
+        }
+    }
+    fn group_voices(&self) -> bool {
+        match self {
+            Self::Instrument { group_voices, .. } => *group_voices,
+            _ => false,
         }
     }
     pub fn to_nvoices(&self) -> i32 {
         match self {
             Self::AutoDetect => -1,
             Self::Effect => 0,
-            Self::Instrument { nvoices } => *nvoices,
+            Self::Instrument { nvoices, .. } => *nvoices,
         }
     }
 }
 
+/// A factory deleted once the last [`SingletonDsp`] using it is dropped. The
+/// DSPs loaded by identical requests share the same factory (see
+/// [`CompileQueue::run`])
+#[derive(Debug)]
+struct OwnedFactory(AtomicPtr<WFactory>);
+
+impl Drop for OwnedFactory {
+    fn drop(&mut self) {
+        let factory = AtomicPtr::new(*self.0.get_mut());
+        // libfaust's table of factories must not change during a compilation,
+        // but the DSP may be dropped by a thread that must not wait for one:
+        CompileQueue::global().defer(move || unsafe { w_deleteDSPFactory(factory.into_inner()) });
+    }
+}
+
 #[derive(Debug)]
 /// RAII interface to faust DSP factories and instances
 pub struct SingletonDsp {
-    transport_already_playing: AtomicBool,
-    /// The factory pointer is kept around only to be deallocated when its time
-    /// to drop the SingletonDsp
-    factory: AtomicPtr<WFactory>,
+    /// Only ever locked by the thread calling [`Self::handle_midi_sync`]
+    midi_clock: Mutex<MidiClock>,
+    /// Only ever locked by the thread calling [`Self::handle_raw_midi`] and
+    /// [`Self::handle_note_on`]. The settings changed by other threads are
+    /// handed over via `midi_settings`
+    midi_input: Mutex<MidiInputState>,
+    /// Locked by the threads changing how MIDI input is handled, but only ever
+    /// try-locked by the one handling MIDI input, so that it never waits
+    midi_settings: Mutex<PendingMidiSettings>,
+    /// The first controller received since the last call to
+    /// [`Self::take_learned_cc`], as `channel << 8 | cc`, or -1
+    learned_cc: AtomicI32,
+    /// The factory is kept around only to be deallocated when its time to drop
+    /// the SingletonDsp (and the other ones sharing it). `None` if the factory
+    /// is not owned
+    factory: Option<Arc<OwnedFactory>>,
     /// The DSP instance is mutex-protected, as we don't want its compute
     /// function being called by two threads at the same time
     instance: Mutex<AtomicPtr<WDsp>>,
+    /// The polyphonic part of the instance, null if the DSP is not an
+    /// instrument
+    poly: AtomicPtr<WPoly>,
+    /// The part of the instance applying the changes of value scheduled with
+    /// [`Self::schedule_param`], null if the instance was not created by
+    /// [`Self::from_file`] & co
+    scheduled: AtomicPtr<WScheduledZones>,
     uis: AtomicPtr<WUIs>,
     /// The static lifetime here is just to simplify the implementation. It will
     /// never be seen from the outside, as widgets' zones are only valid as long
     /// as the whole SingletonDsp is valid (as they point to values that are
     /// contained inside the WDsp object).
     widgets: RwLock<Vec<DspWidget<&'static mut f32>>>,
+    /// Written only once the widgets are built, so that reading it never
+    /// requires locking
+    zones: DspZones,
     chan_ptrs: ChanPtrs,
     /// Tells the sample rate and how many input & output audio channels this
     /// DSP expects
     pub info: DspInfo,
+    pub factory_source: FactorySource,
 }
 // AtomicPtr is used above only to make the pointers (and thus the whole type)
 // Sync. The pointers themselves will never be mutated.
@@ -117,22 +214,26 @@ impl Drop for SingletonDsp {
             if !uis.is_null() {
                 w_deleteUIs(*uis);
             }
-            let factory = self.factory.get_mut();
-            if !factory.is_null() {
-                w_deleteDSPFactory(*factory);
-            }
         }
+        // The factory is deleted after the instance, when the field is dropped
     }
 }
 
-/// Data needed to generate a MIDI clock for the DSP
-pub struct ClockData {
-    /// The tempo (as given by the host)
-    pub tempo: f64,
-    /// The buffer size
-    pub next_buffer_size: usize,
-    /// Where we are in the track (expressed in samples)
-    pub next_buffer_sample_position: i64,
+/// Identifies a parameter of the DSP
+#[derive(Debug, Clone, Copy)]
+pub enum ParamRef<'a> {
+    /// The full path of the parameter's widget, ie. the labels of its
+    /// enclosing boxes and its own label, separated by slashes (eg.
+    /// `"/synth/env/attack"`). Boxes with an empty label are skipped
+    Path(&'a str),
+    /// The pointer to the parameter's zone, as given by [`Zone::as_ptr`]
+    Zone(*mut f32),
+}
+
+impl<'a> From<&'a str> for ParamRef<'a> {
+    fn from(path: &'a str) -> Self {
+        Self::Path(path)
+    }
 }
 
 fn path_to_cstring(p: &Path) -> Result<CString, String> {
@@ -143,11 +244,17 @@ fn path_to_cstring(p: &Path) -> Result<CString, String> {
 impl SingletonDsp {
     fn new_empty() -> Self {
         Self {
-            transport_already_playing: AtomicBool::new(false),
-            factory: AtomicPtr::new(null_mut()),
+            midi_clock: Mutex::new(MidiClock::default()),
+            midi_input: Mutex::new(MidiInputState::default()),
+            midi_settings: Mutex::new(PendingMidiSettings::default()),
+            learned_cc: AtomicI32::new(-1),
+            factory: None,
             instance: Mutex::new(AtomicPtr::new(null_mut())),
+            poly: AtomicPtr::new(null_mut()),
+            scheduled: AtomicPtr::new(null_mut()),
             uis: AtomicPtr::new(null_mut()),
             widgets: RwLock::new(vec![]),
+            zones: DspZones::default(),
             chan_ptrs: ChanPtrs {
                 vec: RefCell::new(vec![]),
             },
@@ -155,68 +262,116 @@ impl SingletonDsp {
                 sample_rate: 0,
                 num_inputs: 0,
                 num_outputs: 0,
+                latency: 0,
             },
+            factory_source: FactorySource::External,
+        }
+    }
+
+    /// Runs the helper, which writes the factory of the script to the cache.
+    /// Not done through the [`CompileQueue`], as the helper process has its own
+    /// libfaust. Returns the folder the factory can then be read from
+    fn compile_with_helper(
+        opt_cache: Option<&Cache>,
+        helper: &CompileHelper,
+        script_path: &Path,
+        import_paths: &[&Path],
+    ) -> Result<(PathBuf, FactorySource), String> {
+        let cache = opt_cache.ok_or("Compiling in a helper process requires a cache folder")?;
+        // We are not including import_paths in the hash as it takes too long
+        // too hash. Improve this later
+        let res_id = Cache::hash_input(script_path, &[]).map_err(|e| e.to_string())?;
+        match cache.query(res_id) {
+            CacheQueryResult::Hit(folder) => Ok((folder, FactorySource::CacheHit)),
+            CacheQueryResult::Miss(writer) => {
+                writer.try_with_dest_folder(|folder| {
+                    helper.compile(script_path, import_paths, folder)
+                })?;
+                Ok((writer.final_folder().to_owned(), FactorySource::Helper))
+            }
         }
     }
 
-    fn add_factory(
-        &mut self,
+    /// Reads the factory from `compiled_folder` if given, otherwise compiles
+    /// the script (unless it is in the cache). To be called through the
+    /// [`CompileQueue`]
+    fn new_factory(
         opt_cache: Option<&Cache>,
+        compiled_folder: Option<&(PathBuf, FactorySource)>,
         script_path: &Path,
         import_paths: &[&Path],
-    ) -> Result<(), String> {
+    ) -> Result<(Arc<OwnedFactory>, FactorySource), String> {
         let mut error_msg_buf = [0; 4096];
-        let fac_ptr = match opt_cache {
-            Some(cache) => {
+        let (fac_ptr, source) = match (compiled_folder, opt_cache) {
+            (Some((folder, source)), _) => (
+                read_factory_from_folder(folder, &mut error_msg_buf)?,
+                *source,
+            ),
+            (None, Some(cache)) => {
                 // We are not including import_paths in the hash as it takes too
                 // long too hash. Improve this later
                 let res_id = Cache::hash_input(script_path, &[]).map_err(|e| e.to_string())?;
                 match cache.query(res_id) {
-                    CacheQueryResult::Hit(folder) => unsafe {
-                        w_readFactoryFromFolder(
-                            path_to_cstring(&folder)?.as_ptr(),
-                            error_msg_buf.as_mut_ptr(),
-                        )
-                    },
+                    CacheQueryResult::Hit(folder) => (
+                        read_factory_from_folder(&folder, &mut error_msg_buf)?,
+                        FactorySource::CacheHit,
+                    ),
                     CacheQueryResult::Miss(writer) => {
                         let fac_ptr =
                             new_factory_from_file(script_path, import_paths, &mut error_msg_buf)?;
-                        writer.with_dest_folder(|folder| {
+                        let fac_ptr = writer.with_dest_folder(|folder| {
                             unsafe {
                                 w_writeFactoryToFolder(fac_ptr, path_to_cstring(folder)?.as_ptr());
                             };
                             Ok::<_, String>(fac_ptr)
-                        })?
+                        })?;
+                        (fac_ptr, FactorySource::CacheMiss)
                     }
                 }
             }
-            None => new_factory_from_file(script_path, import_paths, &mut error_msg_buf)?,
+            (None, None) => (
+                new_factory_from_file(script_path, import_paths, &mut error_msg_buf)?,
+                FactorySource::Compiled,
+            ),
         };
         if fac_ptr.is_null() {
-            let error_msg = unsafe { CStr::from_ptr(error_msg_buf.as_ptr()) };
-            Err(error_msg
-                .to_str()
-                .map_err(|s| format!("Could not parse Faust err msg as utf8: {}", s))?
-                .to_string())
+            Err(faust_error(&error_msg_buf))
         } else {
-            *self.factory.get_mut() = fac_ptr;
-            Ok(())
+            Ok((Arc::new(OwnedFactory(AtomicPtr::new(fac_ptr))), source))
         }
     }
 
-    fn add_instance(&mut self, sample_rate: i32, load_mode: &DspLoadMode) {
+    fn add_instance(&mut self, factory: *mut WFactory, sample_rate: i32, load_mode: &DspLoadMode) {
         *self.instance.get_mut().unwrap().get_mut() = unsafe {
             w_createDSPInstance(
-                *self.factory.get_mut(),
+                factory,
                 sample_rate,
                 load_mode.to_nvoices(),
-                false,
+                load_mode.group_voices(),
             )
         };
+        if let DspLoadMode::Instrument {
+            voice_policy,
+            release,
+            mpe,
+            ..
+        } = load_mode
+        {
+            let poly = unsafe { w_getPoly(*self.instance.get_mut().unwrap().get_mut()) };
+            if !poly.is_null() {
+                unsafe { w_setVoicePolicy(poly, voice_policy.to_w_policy()) };
+                unsafe { w_setCutOnRelease(poly, *release == VoiceRelease::Cut) };
+                if let Some(mpe) = mpe {
+                    unsafe { w_setMpeConfig(poly, &mpe.to_w_config()) };
+                }
+            }
+        }
     }
 
     fn add_info_and_uis(&mut self) {
         let inst_ptr = *self.instance.get_mut().unwrap().get_mut();
+        *self.poly.get_mut() = unsafe { w_getPoly(inst_ptr) };
+        *self.scheduled.get_mut() = unsafe { w_getScheduledZones(inst_ptr) };
         self.info = unsafe { w_getDSPInfo(inst_ptr) };
         *self.chan_ptrs.vec.get_mut() =
             vec![null_mut(); self.info.num_inputs.max(self.info.num_outputs) as usize];
@@ -227,7 +382,11 @@ impl SingletonDsp {
                 (&mut widgets_builder) as *mut DspWidgetsBuilder as *mut c_void,
             )
         };
-        widgets_builder.build_widgets(self.widgets.get_mut().unwrap());
+        let widgets = self.widgets.get_mut().unwrap();
+        self.zones = widgets_builder.build_widgets(widgets);
+        if let Some(poly_widgets) = PolyWidgets::find(widgets) {
+            self.zones.voices = poly_widgets.voice_zones();
+        }
     }
 
     /// Load a faust .dsp file and initialize the DSP
@@ -238,6 +397,9 @@ impl SingletonDsp {
     /// Can use a file-based [`Cache`] to store the LLVM bytecode to save time when
     /// reloading the same DSP in a future execution. IMPORTANT: That cache
     /// takes into account only the contents of the script, NOT what it imports
+    ///
+    /// The script is compiled through the [`CompileQueue::global`] queue, so
+    /// this can be called from several threads at the same time
     pub fn from_file(
         opt_cache: Option<&Cache>,
         script_path: &Path,
@@ -245,11 +407,66 @@ impl SingletonDsp {
         sample_rate: i32,
         load_mode: &DspLoadMode,
     ) -> Result<Self, String> {
-        let mut dsp = Self::new_empty();
-        dsp.add_factory(opt_cache, script_path, import_paths)?;
-        dsp.add_instance(sample_rate, load_mode);
-        dsp.add_info_and_uis();
-        Ok(dsp)
+        Self::from_file_queued(
+            opt_cache,
+            None,
+            script_path,
+            import_paths,
+            sample_rate,
+            load_mode,
+            CompileRequester::new(),
+            |_| {},
+        )
+        .expect("A request from a new requester cannot be superseded")
+    }
+
+    /// Like [`Self::from_file`], but tells `on_position` where the compilation
+    /// is in the [`CompileQueue::global`] queue (see [`CompileQueue::run`]).
+    /// Returns `None` if another call from the same `requester` superseded this
+    /// one before it started compiling
+    ///
+    /// If `opt_helper` is given, the script is compiled in a separate process
+    /// (see [`CompileHelper`]), which requires a cache. The helper doesn't
+    /// wait for the queue (`on_position` is called with 0 while it runs), only
+    /// the reading of the factory it wrote does
+    #[allow(clippy::too_many_arguments)]
+    pub fn from_file_queued(
+        opt_cache: Option<&Cache>,
+        opt_helper: Option<&CompileHelper>,
+        script_path: &Path,
+        import_paths: &[&Path],
+        sample_rate: i32,
+        load_mode: &DspLoadMode,
+        requester: CompileRequester,
+        mut on_position: impl FnMut(usize),
+    ) -> Option<Result<Self, String>> {
+        let compiled_folder = match opt_helper {
+            Some(helper) => {
+                on_position(0);
+                match Self::compile_with_helper(opt_cache, helper, script_path, import_paths) {
+                    Ok(compiled) => Some(compiled),
+                    Err(msg) => return Some(Err(msg)),
+                }
+            }
+            None => None,
+        };
+        let key = format!("{:?} {:?}", script_path, import_paths);
+        let shared = CompileQueue::global().run(requester, &key, &mut on_position, || {
+            Self::new_factory(
+                opt_cache,
+                compiled_folder.as_ref(),
+                script_path,
+                import_paths,
+            )
+        })?;
+        Some(shared.map(|(factory, source)| {
+            let mut dsp = Self::new_empty();
+            dsp.add_instance(factory.0.load(Ordering::Relaxed), sample_rate, load_mode);
+            dsp.add_info_and_uis();
+            dsp.factory = Some(factory);
+            dsp.factory_source = source;
+            dsp
+        }))
     }
 
     /// Creates a SingletonDsp from an already created `dsp_poly_factory` (the
@@ -276,12 +493,11 @@ impl SingletonDsp {
         load_mode: &DspLoadMode,
     ) -> Self {
         let mut dsp = Self::new_empty();
-        *dsp.factory.get_mut() = factory_ptr;
-        dsp.add_instance(sample_rate, load_mode);
+        dsp.add_instance(factory_ptr, sample_rate, load_mode);
         dsp.add_info_and_uis();
-        if !owns_factory {
-            // We don't own the factory and therefore don't keep its pointer
-            *dsp.factory.get_mut() = null_mut();
+        if owns_factory {
+            // We don't keep the pointer of a factory we don't own
+            dsp.factory = Some(Arc::new(OwnedFactory(AtomicPtr::new(factory_ptr))));
         }
         dsp
     }
@@ -315,18 +531,390 @@ impl SingletonDsp {
         f(&mut *self.widgets.write().unwrap())
     }
 
+    /// Like [`Self::with_widgets_mut`], but gives only the widgets of the
+    /// voices, or None if the DSP was not loaded as an instrument with
+    /// ungrouped voices (see [`PolyWidgets::find`])
+    pub fn with_poly_widgets_mut<T>(
+        &self,
+        f: impl FnOnce(Option<PolyWidgets<'_, &mut f32>>) -> T,
+    ) -> T {
+        self.with_widgets_mut(|widgets| f(PolyWidgets::find(widgets)))
+    }
+
     /// To be called for each midi event for the current audio buffer
     ///
+    /// `midi_data` can contain any number of complete MIDI messages, including
+    /// SysEx ones (of up to 4096 bytes), and running status is supported.
+    /// Besides what Faust itself handles, this deals with 14-bit controllers:
+    /// widgets with a `[midi:ctrl N]` metadata (with N < 32) get the full
+    /// 14-bit value when the LSB is sent via CC N+32, and widgets with a
+    /// `[midi:nrpn N]` metadata are set via NRPN N (with 14-bit data entry)
+    ///
+    /// The messages first go through the DSP's [`MidiTransform`] (see
+    /// [`Self::set_midi_transform`]). Controllers then also drive the widgets
+    /// they are mapped to at runtime (see [`Self::set_cc_mappings`])
+    ///
     /// See [`Self::process_buffers`] for more info
-    pub fn handle_raw_midi(&self, timestamp: f64, midi_data: [u8; 3]) {
+    pub fn handle_raw_midi(&self, timestamp: f64, midi_data: &[u8]) {
+        let mut midi_input = self.lock_midi_input();
+        self.handle_midi_locked(&mut midi_input, timestamp, midi_data);
+    }
+
+    /// Locks the state of MIDI input, after having taken into account the
+    /// settings changed since the last time
+    fn lock_midi_input(&self) -> MutexGuard<'_, MidiInputState> {
+        let mut midi_input = self.midi_input.lock().unwrap();
+        midi_input.pick_up_settings(&self.midi_settings);
+        midi_input
+    }
+
+    fn handle_midi_locked(
+        &self,
+        midi_input: &mut MidiInputState,
+        timestamp: f64,
+        midi_data: &[u8],
+    ) {
         let uis = self.uis.load(Ordering::Relaxed);
-        unsafe {
-            w_handleRawMidi(uis, timestamp, midi_data.as_ptr());
+        let MidiInputState {
+            parser,
+            hi_res,
+            transform,
+            cc_mappings,
+        } = midi_input;
+        let set_zone = |&ZonePtr(zone): &ZonePtr, value| unsafe {
+            if !self.schedule_zone_change(zone, timestamp, value) {
+                *zone = value;
+            }
+        };
+        parser.parse(midi_data, |full_msg| {
+            transform.apply(full_msg, |full_msg| {
+                unsafe {
+                    w_handleRawMidi(uis, timestamp, full_msg.as_ptr(), full_msg.len() as i32)
+                };
+
+                if full_msg[0] & 0xF0 == 0xB0 && full_msg.len() == 3 {
+                    let (channel, cc, value) = (full_msg[0] & 0x0F, full_msg[1], full_msg[2]);
+                    hi_res.control_change(
+                        &self.zones.hi_res_ctrls,
+                        channel,
+                        cc,
+                        value,
+                        |ctrl, value| set_zone(&ctrl.zone, value),
+                    );
+                    cc_mappings.control_change(channel, cc, value, set_zone);
+                    let _ = self.learned_cc.compare_exchange(
+                        -1,
+                        (channel as i32) << 8 | cc as i32,
+                        Ordering::Relaxed,
+                        Ordering::Relaxed,
+                    );
+                }
+            });
+        });
+    }
+
+    /// Start a note, which can be given an id to identify its voice later (see
+    /// [`NoteRef`]). Equivalent to sending a MIDI note on via
+    /// [`Self::handle_raw_midi`] when `note_id` is None. Returns false if the
+    /// note was dropped by the [`MidiTransform`]
+    ///
+    /// See [`Self::process_buffers`] for more info
+    pub fn handle_note_on(&self, timestamp: f64, note: NoteRef, velocity: u8) -> bool {
+        // Keeping the lock, so that the transform cannot change before the
+        // note on goes through it:
+        let mut midi_input = self.lock_midi_input();
+        let dropped = midi_input
+            .transform
+            .transform
+            .map_note(note.channel, note.note)
+            .is_none();
+        if dropped {
+            return false;
+        }
+        let poly = self.poly.load(Ordering::Relaxed);
+        if let (false, Some(note_id)) = (poly.is_null(), note.note_id) {
+            unsafe { w_setNextNoteId(poly, note_id) };
+        }
+        // A note on with a null velocity would be a note off:
+        let bytes = [
+            0x90 | (note.channel & 0x0F),
+            note.note & 0x7F,
+            velocity.clamp(1, 127),
+        ];
+        self.handle_midi_locked(&mut midi_input, timestamp, &bytes);
+        true
+    }
+
+    /// Release a note. If it has an id, only the voice that was started with
+    /// that id is released, even if other voices play the same note. Equivalent
+    /// to sending a MIDI note off via [`Self::handle_raw_midi`] when `note_id`
+    /// is None
+    ///
+    /// See [`Self::process_buffers`] for more info
+    pub fn handle_note_off(&self, timestamp: f64, note: NoteRef, velocity: u8) {
+        let bytes = [
+            0x80 | (note.channel & 0x0F),
+            note.note & 0x7F,
+            velocity & 0x7F,
+        ];
+        let mut midi_input = self.lock_midi_input();
+        let poly = self.poly.load(Ordering::Relaxed);
+        if poly.is_null() {
+            return self.handle_midi_locked(&mut midi_input, timestamp, &bytes);
+        }
+        unsafe { w_setNextNoteOffId(poly, note.note_id.unwrap_or(-1)) };
+        self.handle_midi_locked(&mut midi_input, timestamp, &bytes);
+        // In case the note off was dropped by the MidiTransform:
+        unsafe { w_setNextNoteOffId(poly, -1) };
+    }
+
+    /// The note that the voices started by `note` play, ie. `note` after the
+    /// [`MidiTransform`]. Notes with an id are found by their id, so are left
+    /// unchanged
+    fn transformed_note(&self, note: NoteRef) -> NoteRef {
+        if note.note_id.is_some() {
+            return note;
+        }
+        let midi_input = self.midi_input.lock().unwrap();
+        match midi_input.transform.note_of_key(note.channel, note.note) {
+            Some(transformed) => NoteRef {
+                note: transformed,
+                ..note
+            },
+            None => note,
+        }
+    }
+
+    /// Change how the MIDI messages are transformed before reaching the DSP,
+    /// from the next MIDI message on. Notes that are already held are still
+    /// released correctly
+    pub fn set_midi_transform(&self, transform: MidiTransform) {
+        self.midi_settings.lock().unwrap().transform.set(transform);
+    }
+
+    /// Change the timbre of the voice(s) playing a note. Does nothing if the
+    /// DSP is not an instrument. Without `note_id`, `note` is the note before
+    /// the [`MidiTransform`], as given to [`Self::handle_note_on`]
+    ///
+    /// The change is applied at the beginning of the next audio buffer
+    /// processed by [`Self::process_buffers`]
+    pub fn set_note_expression(&self, note: NoteRef, expression: NoteExpression, value: f32) {
+        let poly = self.poly.load(Ordering::Relaxed);
+        if !poly.is_null() {
+            let note = self.transformed_note(note);
+            unsafe {
+                w_setNoteExpression(
+                    poly,
+                    note.to_w_note_ref(),
+                    expression.to_w_expression(),
+                    value,
+                )
+            };
+        }
+    }
+
+    /// Make MIDI controllers drive widgets of the DSP, replacing the previous
+    /// mappings. Mappings whose widget does not exist in this DSP are ignored
+    pub fn set_cc_mappings(&self, mappings: &[CcMapping]) {
+        let active = mappings
+            .iter()
+            .filter_map(|m| Some((m.clone(), *self.zones.by_path.get(&m.path)?)))
+            .collect();
+        self.midi_settings.lock().unwrap().cc_mappings.set(active);
+    }
+
+    /// The channel and number of the first controller received by
+    /// [`Self::handle_raw_midi`] (after the [`MidiTransform`]) since the last
+    /// call, if any. Meant for MIDI learn
+    pub fn take_learned_cc(&self) -> Option<(u8, u8)> {
+        match self.learned_cc.swap(-1, Ordering::Relaxed) {
+            -1 => None,
+            x => Some(((x >> 8) as u8, x as u8)),
+        }
+    }
+
+    /// Release all the voices of the instrument, or if `hard` silence them
+    /// immediately. MIDI All Notes Off (CC123) and All Sound Off (CC120)
+    /// messages received by [`Self::handle_raw_midi`] do the same. Does nothing
+    /// if the DSP is not an instrument
+    ///
+    /// Locks the DSP, so if called from another thread than the audio one this
+    /// will wait for the current buffer to be processed
+    pub fn all_notes_off(&self, hard: bool) {
+        let poly = self.poly.load(Ordering::Relaxed);
+        if !poly.is_null() {
+            let _dsp = self.instance.lock().unwrap();
+            unsafe { w_allNotesOff(poly, hard) };
+        }
+    }
+
+    /// Calls `f` on each note whose voice has terminated (ie. has finished its
+    /// release or has been stolen by another note) since the last call. Should
+    /// be called _after_ [`Self::process_buffers`]. Does not allocate, so can be
+    /// called from the audio thread
+    ///
+    /// The notes are given as they were before the [`MidiTransform`]
+    pub fn take_terminated_notes(&self, mut f: impl FnMut(NoteRef)) {
+        let poly = self.poly.load(Ordering::Relaxed);
+        if poly.is_null() {
+            return;
+        }
+        let mut notes = [WNoteRef {
+            note_id: -1,
+            channel: 0,
+            pitch: 0,
+        }; 64];
+        loop {
+            let n = unsafe { w_popTerminatedNotes(poly, notes.as_mut_ptr(), notes.len() as i32) }
+                as usize;
+            let midi_input = self.midi_input.lock().unwrap();
+            for note in &mut notes[..n] {
+                if let Some(key) = midi_input
+                    .transform
+                    .key_of_note(note.channel as u8, note.pitch as u8)
+                {
+                    note.pitch = key as i32;
+                }
+            }
+            // Not calling `f` with the lock taken, as it may handle MIDI too:
+            drop(midi_input);
+            for note in &notes[..n] {
+                f(NoteRef::from_w_note_ref(note));
+            }
+            if n < notes.len() {
+                break;
+            }
+        }
+    }
+
+    /// Change the frequency given to the voices for each MIDI note (None to go
+    /// back to standard 12-TET tuning). Notes that are already playing keep
+    /// their frequency. Does nothing if the DSP is not an instrument. Does not
+    /// lock the DSP, so can be called from any thread
+    pub fn set_tuning(&self, tuning: Option<&Tuning>) {
+        let poly = self.poly.load(Ordering::Relaxed);
+        if !poly.is_null() {
+            let freqs = tuning.map_or(std::ptr::null(), |t| t.freqs().as_ptr());
+            unsafe { w_setTuning(poly, freqs) };
+        }
+    }
+
+    /// The number of voices of the instrument, or 0 if the DSP is not an
+    /// instrument. Does not allocate
+    pub fn num_voices(&self) -> usize {
+        let poly = self.poly.load(Ordering::Relaxed);
+        if poly.is_null() {
+            return 0;
+        }
+        unsafe { w_getVoiceStates(poly, null_mut(), 0) as usize }
+    }
+
+    /// The state of each voice of the instrument (None for the voices that are
+    /// free), or an empty Vec if the DSP is not an instrument. Does not lock
+    /// the DSP, so can be called often (eg. on each GUI frame) from any thread,
+    /// but allocates
+    pub fn voice_states(&self) -> Vec<Option<VoiceState>> {
+        let poly = self.poly.load(Ordering::Relaxed);
+        if poly.is_null() {
+            return vec![];
+        }
+        let nvoices = self.num_voices();
+        let free = WVoiceState {
+            activity: WVoiceActivity::VOICE_FREE,
+            pitch: 0,
+            velocity: 0,
+            age: 0,
+        };
+        let mut states = vec![free; nvoices];
+        unsafe { w_getVoiceStates(poly, states.as_mut_ptr(), nvoices as i32) };
+        states
+            .iter()
+            .map(|state| VoiceState::from_w_voice_state(state, self.info.sample_rate))
+            .collect()
+    }
+
+    /// Queue a change of value for some parameter, which will be applied
+    /// `sample_offset` samples after the beginning of the next audio buffer
+    /// processed by [`Self::process_buffers`] (in a later buffer if the offset
+    /// is beyond the end of that one). This is the equivalent of
+    /// [`Self::handle_raw_midi`] for direct parameter changes, and allows
+    /// sub-buffer accuracy for parameter automation.
+    ///
+    /// Returns false if the parameter couldn't be found, if it isn't an input
+    /// parameter (ie. if it is a bargraph), or if too many changes are already
+    /// queued for it. Does not allocate, so can be called from the audio thread
+    ///
+    /// See [`Self::process_buffers`] for more info
+    pub fn schedule_param<'a>(
+        &self,
+        param: impl Into<ParamRef<'a>>,
+        value: f32,
+        sample_offset: usize,
+    ) -> bool {
+        let zone = match param.into() {
+            ParamRef::Path(path) => match self.zones.by_path.get(path) {
+                Some(ZonePtr(zone)) => *zone,
+                None => return false,
+            },
+            ParamRef::Zone(zone) => zone,
+        };
+        self.schedule_zone_change(zone, sample_offset as f64, value)
+    }
+
+    /// Like [`Self::schedule_param`], but only for the voice(s) playing a note.
+    /// `param` must be one of the widgets controlling all the voices, and the
+    /// DSP must have been loaded as an instrument with ungrouped voices (see
+    /// [`PolyWidgets`]). The voices get the value of `param` again each time it
+    /// changes
+    ///
+    /// Returns false if the parameter has no per-voice widgets, or if no voice
+    /// plays the note. Does not allocate, so can be called from the audio
+    /// thread
+    pub fn schedule_voice_param<'a>(
+        &self,
+        note: NoteRef,
+        param: impl Into<ParamRef<'a>>,
+        value: f32,
+        sample_offset: usize,
+    ) -> bool {
+        let poly = self.poly.load(Ordering::Relaxed);
+        let zone = match param.into() {
+            ParamRef::Path(path) => self.zones.by_path.get(path).copied(),
+            ParamRef::Zone(zone) => Some(ZonePtr(zone)),
+        };
+        let Some(voice_zones) = zone.and_then(|zone| self.zones.voices.get(&zone)) else {
+            return false;
+        };
+        if poly.is_null() {
+            return false;
+        }
+        let note = self.transformed_note(note);
+        let mut found = false;
+        for (voice, &ZonePtr(zone)) in voice_zones.iter().enumerate() {
+            if !zone.is_null() && unsafe { w_playsNote(poly, voice as i32, note.to_w_note_ref()) } {
+                found = true;
+                if !self.schedule_zone_change(zone, sample_offset as f64, value) {
+                    unsafe { *zone = value };
+                }
+            }
         }
+        found
     }
 
-    /// Generate a MIDI clock and MIDI start/stop messages, and send them to the
-    /// DSP
+    fn schedule_zone_change(&self, zone: *mut f32, time: f64, value: f32) -> bool {
+        let scheduled = self.scheduled.load(Ordering::Relaxed);
+        !scheduled.is_null() && unsafe { w_scheduleZoneChange(scheduled, zone, time, value) }
+    }
+
+    /// Generate a MIDI clock and MIDI start/stop/continue and song position
+    /// messages, and send them to the DSP
+    ///
+    /// The clock is a 24 PPQN one, aligned on
+    /// [`ClockData::next_buffer_sample_position`] with sub-sample precision, so
+    /// it doesn't drift from the host's grid. When playback starts somewhere
+    /// else than at the beginning of the track (or when the playhead jumps),
+    /// a song position pointer followed by a MIDI continue are sent instead of
+    /// a MIDI start.
     ///
     /// IMPORTANT: Call this ONLY if your MIDI event source does not already
     /// include these messages (else, simply forward them via
@@ -334,42 +922,65 @@ impl SingletonDsp {
     ///
     /// See [`Self::process_buffers`] for more info
     pub fn handle_midi_sync(&self, playing: bool, opt_clock_data: &Option<ClockData>) {
-        let already_playing = self.transport_already_playing.load(Ordering::Relaxed);
         let uis = self.uis.load(Ordering::Relaxed);
-        if playing {
-            if !already_playing {
-                unsafe { w_handleMidiSync(uis, 0.0, WMidiSyncMsg::MIDI_START) };
-                self.transport_already_playing
-                    .store(true, Ordering::Relaxed);
-            }
-
-            // We generate and send to the DSP a 24 PPQN clock:
-            if let Some(clock_data) = opt_clock_data {
-                let samples_per_beat = (self.info.sample_rate as f64) * 60.0 / clock_data.tempo;
-                let samples_per_pulse = (samples_per_beat / 24.0) as i64;
-
-                // next_pulse_pos is in buffer coordinates (ie. 0 is the first sample of
-                // the current buffer)
-                let rem = clock_data.next_buffer_sample_position % samples_per_pulse;
-                let mut next_pulse_pos = if rem == 0 { 0 } else { samples_per_pulse - rem };
-                while next_pulse_pos < clock_data.next_buffer_size as i64 {
-                    unsafe {
-                        w_handleMidiSync(uis, next_pulse_pos as f64, WMidiSyncMsg::MIDI_CLOCK)
-                    };
-                    next_pulse_pos += samples_per_pulse;
+        let mut midi_clock = self.midi_clock.lock().unwrap();
+        midi_clock.next_buffer(
+            self.info.sample_rate as f64,
+            playing,
+            opt_clock_data,
+            |offset, event| {
+                let time = offset as f64;
+                match event {
+                    SyncEvent::Start => unsafe {
+                        w_handleMidiSync(uis, time, WMidiSyncMsg::MIDI_START)
+                    },
+                    SyncEvent::Continue => unsafe {
+                        w_handleMidiSync(uis, time, WMidiSyncMsg::MIDI_CONT)
+                    },
+                    SyncEvent::Stop => unsafe {
+                        w_handleMidiSync(uis, time, WMidiSyncMsg::MIDI_STOP)
+                    },
+                    SyncEvent::Clock => unsafe {
+                        w_handleMidiSync(uis, time, WMidiSyncMsg::MIDI_CLOCK)
+                    },
+                    SyncEvent::SongPosition(sixteenths) => {
+                        let bytes = [0xF2, (sixteenths & 0x7F) as u8, (sixteenths >> 7) as u8];
+                        unsafe { w_handleRawMidi(uis, time, bytes.as_ptr(), 3) }
+                    }
                 }
-            }
-        } else {
-            if already_playing {
-                unsafe { w_handleMidiSync(uis, 0.0, WMidiSyncMsg::MIDI_STOP) };
-                self.transport_already_playing
-                    .store(false, Ordering::Relaxed);
-            }
+            },
+        );
+    }
+
+    /// Write the host's transport values to the widgets bound to them with a
+    /// `[host:bpm]`, `[host:beat]`, `[host:bar]` or `[host:playing]`
+    /// metadata (see [`HostValue`])
+    ///
+    /// Values are updated at the beginning of the buffer, and then on each new
+    /// beat or bar within the buffer for `[host:beat]` and `[host:bar]`. This is
+    /// sample-accurate for sliders, nentries and buttons, but bargraphs can
+    /// only be updated once per buffer
+    ///
+    /// See [`Self::process_buffers`] for more info
+    pub fn handle_transport(&self, transport: &TransportData) {
+        for (host_value, ZonePtr(zone)) in &self.zones.host {
+            transport.host_value_changes(
+                self.info.sample_rate as f64,
+                *host_value,
+                |offset, value| {
+                    let scheduled = self.schedule_zone_change(*zone, offset as f64, value as f32);
+                    if !scheduled && offset == 0 {
+                        // Not a timed zone, we can only set it directly:
+                        unsafe { **zone = value as f32 };
+                    }
+                },
+            );
         }
     }
 
     /// Modifies _in place_ the given channels. Should be called _after_ all
-    /// MIDI events for the current audio buffer have been handled.
+    /// MIDI events, transport data and scheduled parameter changes for the
+    /// current audio buffer have been handled.
     ///
     /// If another thread is already calling this function, this will wait until
     /// it terminates.
@@ -381,8 +992,6 @@ impl SingletonDsp {
     ///     ignored (ie. will stay untouched)
     ///   - if audio_bufs contains LESS channels, this function will panic
     pub fn process_buffers(&self, audio_bufs: &mut [&mut [f32]]) {
-        unsafe { w_updateAllGuis() };
-
         // First thing to do is to lock the DSP:
         let dsp = self.instance.lock().unwrap();
         let mut ptr_vec = self.chan_ptrs.vec.borrow_mut();
@@ -394,6 +1003,50 @@ impl SingletonDsp {
             w_computeDSP(dsp.load(Ordering::Relaxed), samples, ptr_vec.as_mut_ptr());
         }
     }
+
+    /// Calls `f` on each MIDI message emitted by the DSP (ie. by its widgets
+    /// with a `[midi:...]` metadata, like a bargraph with `[midi:ctrl N]`)
+    /// since the last call, with its position in the current buffer. Should be
+    /// called _after_ [`Self::process_buffers`]
+    ///
+    /// The widgets are checked after each slice of the buffer computed between
+    /// two scheduled parameter changes (or MIDI controls), and their messages
+    /// are placed at the last sample of that slice. SysEx messages are not
+    /// supported. Does not allocate, so can be called from the audio thread
+    pub fn take_midi_output(&self, mut f: impl FnMut(f64, &[u8])) {
+        let uis = self.uis.load(Ordering::Relaxed);
+        let mut msgs = [WMidiMessage {
+            time: 0.0,
+            bytes: [0; 3],
+            len: 0,
+        }; 64];
+        loop {
+            let n = unsafe { w_popMidiOutput(uis, msgs.as_mut_ptr(), msgs.len() as i32) } as usize;
+            for msg in &msgs[..n] {
+                f(msg.time, &msg.bytes[..msg.len as usize]);
+            }
+            if n < msgs.len() {
+                break;
+            }
+        }
+    }
+}
+
+/// The error message written by libfaust in an error buffer
+fn faust_error(error_msg_buf: &[c_char; 4096]) -> String {
+    let error_msg = unsafe { CStr::from_ptr(error_msg_buf.as_ptr()) };
+    match error_msg.to_str() {
+        Ok(msg) => msg.to_string(),
+        Err(e) => format!("Could not parse Faust err msg as utf8: {}", e),
+    }
+}
+
+fn read_factory_from_folder(
+    folder: &Path,
+    error_msg_buf: &mut [c_char; 4096],
+) -> Result<*mut WFactory, String> {
+    let folder = path_to_cstring(folder)?;
+    Ok(unsafe { w_readFactoryFromFolder(folder.as_ptr(), error_msg_buf.as_mut_ptr()) })
 }
 
 fn new_factory_from_file(
@@ -0,0 +1,367 @@
+use std::sync::Mutex;
+
+use crate::{
+    cc_mapping::{CcMapping, CcMappings},
+    midi_transform::{MidiTransform, MidiTransformState},
+    ZonePtr,
+};
+
+/// The number of bytes of a (non-SysEx) MIDI message, given its status byte.
+/// Returns None if `status` is not a status byte, or is the start of a SysEx
+pub fn midi_message_len(status: u8) -> Option<usize> {
+    match status {
+        0x80..=0xBF | 0xE0..=0xEF => Some(3),
+        0xC0..=0xDF => Some(2),
+        0xF2 => Some(3),
+        0xF1 | 0xF3 => Some(2),
+        0xF4..=0xFF if status != 0xF7 => Some(1),
+        _ => None,
+    }
+}
+
+/// Splits a stream of bytes into complete MIDI messages, supporting running
+/// status (even across successive streams) and real-time messages interleaved
+/// with the others
+#[derive(Debug, Default)]
+pub(crate) struct MidiParser {
+    running_status: Option<u8>,
+}
+
+impl MidiParser {
+    /// Calls `f` on each complete message found in `bytes`, always with its
+    /// status byte (even if it was sent with running status). Real-time
+    /// messages are given as soon as they are found, even in the middle of
+    /// another message. Incomplete messages and stray bytes are ignored
+    pub(crate) fn parse(&mut self, mut bytes: &[u8], mut f: impl FnMut(&[u8])) {
+        while let Some(&first) = bytes.first() {
+            if first == 0xF0 {
+                // SysEx: everything until the end marker
+                let end = match bytes.iter().position(|&b| b == 0xF7) {
+                    Some(i) => i + 1,
+                    None => return,
+                };
+                f(&bytes[..end]);
+                bytes = &bytes[end..];
+                // SysEx messages cancel running status:
+                self.running_status = None;
+                continue;
+            }
+            if first >= 0xF8 {
+                // Real-time messages do not affect running status:
+                f(&bytes[..1]);
+                bytes = &bytes[1..];
+                continue;
+            }
+            let status = if first & 0x80 != 0 {
+                bytes = &bytes[1..];
+                // Only channel messages set the running status:
+                self.running_status = (first <= 0xEF).then_some(first);
+                first
+            } else {
+                match self.running_status {
+                    Some(status) => status,
+                    None => {
+                        bytes = &bytes[1..];
+                        continue;
+                    }
+                }
+            };
+            let Some(len) = midi_message_len(status) else {
+                continue;
+            };
+            let mut msg = [status, 0, 0];
+            let mut filled = 1;
+            while filled < len {
+                match bytes.first() {
+                    None => return,
+                    Some(&byte) if byte >= 0xF8 => {
+                        f(&[byte]);
+                        bytes = &bytes[1..];
+                    }
+                    // Another message interrupts this one:
+                    Some(&byte) if byte & 0x80 != 0 => break,
+                    Some(&byte) => {
+                        msg[filled] = byte;
+                        filled += 1;
+                        bytes = &bytes[1..];
+                    }
+                }
+            }
+            if filled == len {
+                f(&msg[..len]);
+            }
+        }
+    }
+}
+
+/// Which controller(s) a [`HiResCtrl`] responds to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum HiResCtrlNum {
+    /// A CC between 0 and 31, whose LSB is sent via CC+32 (`[midi:ctrl N]`)
+    Cc(u8),
+    /// A non-registered parameter number (`[midi:nrpn N]`)
+    Nrpn(u16),
+}
+
+/// A widget driven by a 14-bit controller
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct HiResCtrl {
+    pub(crate) num: HiResCtrlNum,
+    /// From 1 to 16, or 0 for any channel (like Faust's `[midi:ctrl N chan]`)
+    pub(crate) channel: u8,
+    pub(crate) zone: ZonePtr,
+    pub(crate) min: f32,
+    pub(crate) max: f32,
+}
+
+const NRPN_MSB: u8 = 99;
+const NRPN_LSB: u8 = 98;
+const RPN_MSB: u8 = 101;
+const RPN_LSB: u8 = 100;
+const DATA_ENTRY_MSB: u8 = 6;
+const DATA_ENTRY_LSB: u8 = 38;
+
+/// Keeps track of the MSBs of the controllers, and of the currently selected
+/// NRPN for each channel, so as to reconstruct 14-bit values
+#[derive(Debug)]
+pub(crate) struct HiResCtrlState {
+    cc_msbs: [[u8; 32]; 16],
+    /// 0x3FFF (the "null" parameter number) when no NRPN is selected
+    nrpns: [u16; 16],
+}
+
+impl Default for HiResCtrlState {
+    fn default() -> Self {
+        Self {
+            cc_msbs: [[0; 32]; 16],
+            nrpns: [0x3FFF; 16],
+        }
+    }
+}
+
+impl HiResCtrlState {
+    /// Updates the state from a control change, and calls `f` on the widgets
+    /// whose value should change, with their new value
+    pub(crate) fn control_change(
+        &mut self,
+        ctrls: &[HiResCtrl],
+        channel: u8,
+        num: u8,
+        value: u8,
+        mut f: impl FnMut(&HiResCtrl, f32),
+    ) {
+        let ch = channel as usize;
+        let mut set_targets = |target, value14: u16| {
+            let t = value14 as f32 / 0x3FFF as f32;
+            for ctrl in ctrls {
+                if ctrl.num == target && (ctrl.channel == 0 || ctrl.channel == channel + 1) {
+                    f(ctrl, ctrl.min + t * (ctrl.max - ctrl.min));
+                }
+            }
+        };
+
+        match num {
+            // The widgets will already get the 7-bit value from Faust:
+            0..=31 => self.cc_msbs[ch][num as usize] = value,
+            32..=63 => {
+                let msb = self.cc_msbs[ch][num as usize - 32];
+                set_targets(HiResCtrlNum::Cc(num - 32), (msb as u16) << 7 | value as u16);
+            }
+            NRPN_MSB => self.nrpns[ch] = (value as u16) << 7 | (self.nrpns[ch] & 0x7F),
+            NRPN_LSB => self.nrpns[ch] = (self.nrpns[ch] & 0x3F80) | value as u16,
+            // Selecting an RPN deselects the NRPN:
+            RPN_MSB | RPN_LSB => self.nrpns[ch] = 0x3FFF,
+            _ => {}
+        }
+
+        // Data entry CCs are also regular CCs, so they were handled above too
+        let nrpn = self.nrpns[ch];
+        if nrpn != 0x3FFF {
+            match num {
+                DATA_ENTRY_MSB => set_targets(HiResCtrlNum::Nrpn(nrpn), (value as u16) << 7),
+                DATA_ENTRY_LSB => {
+                    let msb = self.cc_msbs[ch][DATA_ENTRY_MSB as usize];
+                    set_targets(HiResCtrlNum::Nrpn(nrpn), (msb as u16) << 7 | value as u16);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Everything that needs to be remembered between two incoming MIDI messages
+#[derive(Debug, Default)]
+pub(crate) struct MidiInputState {
+    pub(crate) parser: MidiParser,
+    pub(crate) hi_res: HiResCtrlState,
+    pub(crate) transform: MidiTransformState,
+    pub(crate) cc_mappings: CcMappings,
+}
+
+impl MidiInputState {
+    /// Starts using the settings last changed in `pending`, unless `pending` is
+    /// being changed right now (in which case they will be picked up by the
+    /// next call). Never blocks, allocates nor deallocates
+    pub(crate) fn pick_up_settings(&mut self, pending: &Mutex<PendingMidiSettings>) {
+        if let Ok(mut pending) = pending.try_lock() {
+            pending.transform.take_into(&mut self.transform.transform);
+            pending.cc_mappings.take_into(&mut self.cc_mappings.active);
+        }
+    }
+}
+
+/// A value handed over to the thread handling MIDI input. The value it
+/// replaces there is kept here, so that it gets deallocated by the next thread
+/// setting a value instead of the MIDI thread
+#[derive(Debug)]
+pub(crate) struct Handover<T> {
+    new: Option<T>,
+    old: Option<T>,
+}
+
+impl<T> Default for Handover<T> {
+    fn default() -> Self {
+        Self {
+            new: None,
+            old: None,
+        }
+    }
+}
+
+impl<T> Handover<T> {
+    pub(crate) fn set(&mut self, value: T) {
+        self.old = None;
+        self.new = Some(value);
+    }
+
+    /// Replaces `current` with the value last set, if it wasn't already
+    fn take_into(&mut self, current: &mut T) {
+        if let Some(new) = self.new.take() {
+            // `old` is always None here, so nothing is deallocated:
+            self.old = Some(std::mem::replace(current, new));
+        }
+    }
+}
+
+/// The MIDI input settings changed by other threads than the one handling MIDI
+/// input (see [`MidiInputState::pick_up_settings`])
+#[derive(Debug, Default)]
+pub(crate) struct PendingMidiSettings {
+    pub(crate) transform: Handover<MidiTransform>,
+    pub(crate) cc_mappings: Handover<Vec<(CcMapping, ZonePtr)>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_all(parser: &mut MidiParser, bytes: &[u8]) -> Vec<Vec<u8>> {
+        let mut msgs = vec![];
+        parser.parse(bytes, |msg| msgs.push(msg.to_vec()));
+        msgs
+    }
+
+    #[test]
+    fn running_status_carries_across_calls() {
+        let mut parser = MidiParser::default();
+        assert_eq!(
+            parse_all(&mut parser, &[0x90, 60, 100, 62, 100]),
+            vec![vec![0x90, 60, 100], vec![0x90, 62, 100]]
+        );
+        assert_eq!(parse_all(&mut parser, &[64, 0]), vec![vec![0x90, 64, 0]]);
+        // System common messages cancel it:
+        assert_eq!(
+            parse_all(&mut parser, &[0xF3, 2, 64, 0]),
+            vec![vec![0xF3, 2]]
+        );
+    }
+
+    #[test]
+    fn real_time_bytes_can_come_inside_other_messages() {
+        let mut parser = MidiParser::default();
+        assert_eq!(
+            parse_all(&mut parser, &[0xB0, 0xF8, 7, 0xFA, 100, 0xF8, 10, 64]),
+            vec![
+                vec![0xF8],
+                vec![0xFA],
+                vec![0xB0, 7, 100],
+                vec![0xF8],
+                vec![0xB0, 10, 64]
+            ]
+        );
+    }
+
+    #[test]
+    fn sysex_is_framed_and_cancels_running_status() {
+        let mut parser = MidiParser::default();
+        assert_eq!(
+            parse_all(&mut parser, &[0x90, 60, 100, 0xF0, 1, 2, 0xF7, 62, 100]),
+            vec![vec![0x90, 60, 100], vec![0xF0, 1, 2, 0xF7]]
+        );
+        // Without its end marker, a SysEx is ignored:
+        assert!(parse_all(&mut parser, &[0xF0, 1, 2]).is_empty());
+    }
+
+    fn hi_res_ctrl(num: HiResCtrlNum) -> HiResCtrl {
+        HiResCtrl {
+            num,
+            channel: 0,
+            zone: ZonePtr(std::ptr::null_mut()),
+            min: 0.0,
+            max: 0x3FFF as f32,
+        }
+    }
+
+    /// Sends control changes on channel 1, and returns the values given to the
+    /// controllers (as 14-bit integers, given their range)
+    fn control_changes(
+        state: &mut HiResCtrlState,
+        ctrls: &[HiResCtrl],
+        ccs: &[(u8, u8)],
+    ) -> Vec<u16> {
+        let mut values = vec![];
+        for &(num, value) in ccs {
+            state.control_change(ctrls, 0, num, value, |_, v| values.push(v.round() as u16));
+        }
+        values
+    }
+
+    #[test]
+    fn hi_res_ccs_use_the_last_msb_when_their_lsb_comes() {
+        let ctrls = [hi_res_ctrl(HiResCtrlNum::Cc(1))];
+        let mut state = HiResCtrlState::default();
+        // The MSB alone doesn't give a 14-bit value, the LSB does:
+        assert_eq!(
+            control_changes(&mut state, &ctrls, &[(1, 0x40), (33, 0x05)]),
+            vec![0x40 << 7 | 0x05]
+        );
+        // A new LSB reuses the last MSB:
+        assert_eq!(
+            control_changes(&mut state, &ctrls, &[(33, 0x7F)]),
+            vec![0x40 << 7 | 0x7F]
+        );
+        // Other controllers are ignored:
+        assert!(control_changes(&mut state, &ctrls, &[(2, 1), (34, 1)]).is_empty());
+    }
+
+    #[test]
+    fn nrpn_data_entry() {
+        let ctrls = [hi_res_ctrl(HiResCtrlNum::Nrpn(0x0102))];
+        let mut state = HiResCtrlState::default();
+        let values = control_changes(
+            &mut state,
+            &ctrls,
+            &[
+                (NRPN_MSB, 0x02),
+                (NRPN_LSB, 0x02),
+                (DATA_ENTRY_MSB, 0x10),
+                (DATA_ENTRY_LSB, 0x20),
+            ],
+        );
+        assert_eq!(values, vec![0x10 << 7, 0x10 << 7 | 0x20]);
+        // Selecting an RPN deselects the NRPN:
+        assert!(
+            control_changes(&mut state, &ctrls, &[(RPN_MSB, 0), (DATA_ENTRY_MSB, 1)]).is_empty()
+        );
+    }
+}
@@ -0,0 +1,303 @@
+/// Data needed to generate a MIDI clock for the DSP
+pub struct ClockData {
+    /// The tempo (as given by the host)
+    pub tempo: f64,
+    /// The buffer size
+    pub next_buffer_size: usize,
+    /// Where we are in the track (expressed in samples)
+    pub next_buffer_sample_position: i64,
+}
+
+/// A MIDI sync message generated by a [`MidiClock`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SyncEvent {
+    Start,
+    Continue,
+    Stop,
+    /// A Song Position Pointer, in MIDI beats (ie. sixteenth notes) since the
+    /// beginning of the track
+    SongPosition(u16),
+    Clock,
+}
+
+/// Generates a 24 PPQN MIDI clock from the transport info of each buffer
+///
+/// Pulses are tracked with fractional precision, so they never drift from the
+/// host's grid. The pulse grid is anchored to the track's sample positions
+/// (pulse N happening at sample N*samples_per_pulse when the tempo is
+/// constant), and when the tempo changes between two contiguous buffers, the
+/// grid is re-anchored at the current pulse phase so the clock speeds up or
+/// slows down without jumping. The tempo is considered constant within a
+/// buffer.
+#[derive(Debug, Default)]
+pub(crate) struct MidiClock {
+    playing: bool,
+    /// Where the next buffer should start if the playhead moves continuously
+    expected_position: Option<i64>,
+    /// The sample position at which the pulse phase is `anchor_phase`
+    anchor_sample: f64,
+    anchor_phase: f64,
+    samples_per_pulse: f64,
+    /// The index of the next pulse to send
+    next_pulse: i64,
+}
+
+impl MidiClock {
+    /// Computes the sync messages for the next buffer, and calls `emit` on
+    /// each of them along with their position in the buffer, in chronological
+    /// order
+    pub(crate) fn next_buffer(
+        &mut self,
+        sample_rate: f64,
+        playing: bool,
+        opt_clock_data: &Option<ClockData>,
+        mut emit: impl FnMut(usize, SyncEvent),
+    ) {
+        if !playing {
+            if self.playing {
+                emit(0, SyncEvent::Stop);
+                self.playing = false;
+            }
+            self.expected_position = None;
+            return;
+        }
+
+        let clock_data = match opt_clock_data {
+            Some(clock_data) => clock_data,
+            None => {
+                // No clock can be generated, but we still tell when we start:
+                if !self.playing {
+                    emit(0, SyncEvent::Start);
+                    self.playing = true;
+                }
+                self.expected_position = None;
+                return;
+            }
+        };
+        let pos = clock_data.next_buffer_sample_position;
+        let samples_per_pulse = sample_rate * 60.0 / (clock_data.tempo * 24.0);
+
+        if !self.playing || self.expected_position != Some(pos) {
+            if self.playing {
+                // The playhead jumped (eg. the host is looping):
+                emit(0, SyncEvent::Stop);
+            }
+            self.playing = true;
+            self.resync(pos, samples_per_pulse, &mut emit);
+        } else if samples_per_pulse != self.samples_per_pulse {
+            // The tempo changed, we re-anchor the grid where we are now:
+            self.anchor_phase += (pos as f64 - self.anchor_sample) / self.samples_per_pulse;
+            self.anchor_sample = pos as f64;
+            self.samples_per_pulse = samples_per_pulse;
+        }
+
+        let buffer_end = pos + clock_data.next_buffer_size as i64;
+        loop {
+            let pulse_pos = self.anchor_sample
+                + (self.next_pulse as f64 - self.anchor_phase) * self.samples_per_pulse;
+            // A pulse is sent on the first sample at or after its exact date:
+            let pulse_sample = pulse_pos.ceil() as i64;
+            if pulse_sample >= buffer_end {
+                break;
+            }
+            emit((pulse_sample - pos).max(0) as usize, SyncEvent::Clock);
+            self.next_pulse += 1;
+        }
+        self.expected_position = Some(buffer_end);
+    }
+
+    /// Aligns the pulse grid on the track's start and tells the receiver where
+    /// we are in the track
+    fn resync(
+        &mut self,
+        pos: i64,
+        samples_per_pulse: f64,
+        emit: &mut impl FnMut(usize, SyncEvent),
+    ) {
+        self.anchor_sample = 0.0;
+        self.anchor_phase = 0.0;
+        self.samples_per_pulse = samples_per_pulse;
+        if pos <= 0 {
+            self.next_pulse = 0;
+            emit(0, SyncEvent::Start);
+        } else {
+            // A Song Position Pointer can only point to a sixteenth note (ie.
+            // every 6 pulses), so we resume on the next one:
+            let phase = pos as f64 / samples_per_pulse;
+            let sixteenths = (phase / 6.0).ceil() as i64;
+            self.next_pulse = sixteenths * 6;
+            // Past its 14 bits, the pointer is wrong but the pulses are not:
+            emit(0, SyncEvent::SongPosition(sixteenths.min(0x3FFF) as u16));
+            emit(0, SyncEvent::Continue);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f64 = 44100.0;
+
+    /// Runs the clock over contiguous buffers of pseudo-random sizes, and
+    /// returns the absolute sample positions of the pulses and the other
+    /// events that happened before `end`
+    fn run(
+        clock: &mut MidiClock,
+        start: i64,
+        end: i64,
+        tempo_at: impl Fn(i64) -> f64,
+    ) -> (Vec<i64>, Vec<(i64, SyncEvent)>) {
+        let mut pulses = vec![];
+        let mut others = vec![];
+        let mut pos = start;
+        let mut seed: u32 = 12345;
+        while pos < end {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            let size = 1 + (seed >> 16) as usize % 1024;
+            let clock_data = Some(ClockData {
+                tempo: tempo_at(pos),
+                next_buffer_size: size,
+                next_buffer_sample_position: pos,
+            });
+            clock.next_buffer(SAMPLE_RATE, true, &clock_data, |offset, ev| {
+                assert!(offset < size, "event outside of buffer");
+                let ev_pos = pos + offset as i64;
+                match ev {
+                    _ if ev_pos >= end => {}
+                    SyncEvent::Clock => pulses.push(ev_pos),
+                    _ => others.push((ev_pos, ev)),
+                }
+            });
+            pos += size as i64;
+        }
+        (pulses, others)
+    }
+
+    #[test]
+    fn constant_tempo_does_not_drift() {
+        let tempo = 123.37;
+        let spp = SAMPLE_RATE * 60.0 / (tempo * 24.0);
+        let end = SAMPLE_RATE as i64 * 60 * 30; // Half an hour
+        let mut clock = MidiClock::default();
+        let (pulses, others) = run(&mut clock, 0, end, |_| tempo);
+
+        assert_eq!(others, vec![(0, SyncEvent::Start)]);
+        let expected: Vec<i64> = (0..)
+            .map(|k| (k as f64 * spp).ceil() as i64)
+            .take_while(|&p| p < end)
+            .collect();
+        assert_eq!(pulses, expected);
+    }
+
+    #[test]
+    fn resuming_mid_song_sends_song_position_and_continue() {
+        let tempo = 90.0;
+        let spp = SAMPLE_RATE * 60.0 / (tempo * 24.0);
+        let start = 1_000_003;
+        let mut clock = MidiClock::default();
+        let (pulses, others) = run(&mut clock, start, start + 100_000, |_| tempo);
+
+        let sixteenths = (start as f64 / spp / 6.0).ceil() as i64;
+        assert_eq!(
+            others,
+            vec![
+                (start, SyncEvent::SongPosition(sixteenths as u16)),
+                (start, SyncEvent::Continue)
+            ]
+        );
+        assert_eq!(pulses[0], (sixteenths as f64 * 6.0 * spp).ceil() as i64);
+        assert_eq!(pulses[1], ((sixteenths * 6 + 1) as f64 * spp).ceil() as i64);
+    }
+
+    #[test]
+    fn resuming_past_the_song_position_range_keeps_the_pulses_in_phase() {
+        let tempo = 120.0;
+        let spp = SAMPLE_RATE * 60.0 / (tempo * 24.0);
+        // About 1100 bars of 4/4, past the 0x3FFF sixteenths of a pointer:
+        let start = 100_000_000;
+        let end = start + 100_000;
+        let mut clock = MidiClock::default();
+        let (pulses, others) = run(&mut clock, start, end, |_| tempo);
+
+        assert_eq!(
+            others,
+            vec![
+                (start, SyncEvent::SongPosition(0x3FFF)),
+                (start, SyncEvent::Continue)
+            ]
+        );
+        let first_pulse = (start as f64 / spp / 6.0).ceil() as i64 * 6;
+        let expected: Vec<i64> = (first_pulse..)
+            .map(|k| (k as f64 * spp).ceil() as i64)
+            .take_while(|&p| p < end)
+            .collect();
+        assert_eq!(pulses, expected);
+    }
+
+    #[test]
+    fn tempo_changes_keep_the_pulse_phase() {
+        // The tempo doubles after 10 seconds:
+        let change = SAMPLE_RATE as i64 * 10;
+        let end = change * 2;
+        let tempo_at = |pos| if pos < change { 100.0 } else { 200.0 };
+        let mut clock = MidiClock::default();
+        let (pulses, _) = run(&mut clock, 0, end, tempo_at);
+
+        // The clock sees the change at the start of the first buffer that
+        // begins after it, so we have to find that buffer:
+        let switch = {
+            let mut clock = MidiClock::default();
+            let (_, _) = run(&mut clock, 0, change, tempo_at);
+            clock.expected_position.unwrap() as f64
+        };
+        let spp_before = SAMPLE_RATE * 60.0 / (100.0 * 24.0);
+        let spp_after = spp_before / 2.0;
+        let switch_phase = switch / spp_before;
+        let expected: Vec<i64> = (0..)
+            .map(|k| {
+                let before = k as f64 * spp_before;
+                if before.ceil() < switch {
+                    before.ceil() as i64
+                } else {
+                    (switch + (k as f64 - switch_phase) * spp_after).ceil() as i64
+                }
+            })
+            .take_while(|&p| p < end)
+            .collect();
+        assert_eq!(pulses, expected);
+    }
+
+    #[test]
+    fn stopping_and_jumping() {
+        let mut clock = MidiClock::default();
+        let data = |pos| {
+            Some(ClockData {
+                tempo: 120.0,
+                next_buffer_size: 512,
+                next_buffer_sample_position: pos,
+            })
+        };
+        let mut events = vec![];
+        let mut collect = |offset, ev| events.push((offset, ev));
+        clock.next_buffer(SAMPLE_RATE, true, &data(0), &mut collect);
+        clock.next_buffer(SAMPLE_RATE, true, &data(512), &mut collect);
+        // Jumping back to the start (eg. looping):
+        clock.next_buffer(SAMPLE_RATE, true, &data(0), &mut collect);
+        clock.next_buffer(SAMPLE_RATE, false, &data(512), &mut collect);
+        clock.next_buffer(SAMPLE_RATE, false, &data(512), &mut collect);
+        let non_clock: Vec<_> = events
+            .into_iter()
+            .filter(|(_, ev)| *ev != SyncEvent::Clock)
+            .collect();
+        assert_eq!(
+            non_clock,
+            vec![
+                (0, SyncEvent::Start),
+                (0, SyncEvent::Stop),
+                (0, SyncEvent::Start),
+                (0, SyncEvent::Stop)
+            ]
+        );
+    }
+}
@@ -0,0 +1,267 @@
+use std::ops::RangeInclusive;
+
+/// How the velocity of note ons is changed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VelocityCurve {
+    /// Keeps the velocity as is
+    Linear,
+    /// Raises the velocity (as a value between 0 and 1) to the given power.
+    /// Above 1 makes soft notes softer, below 1 makes them louder
+    Power(f32),
+    /// Gives the same velocity to every note
+    Fixed(u8),
+}
+
+impl VelocityCurve {
+    fn apply(self, velocity: u8) -> u8 {
+        let v = match self {
+            Self::Linear => velocity,
+            Self::Power(exponent) => {
+                (127.0 * (velocity as f32 / 127.0).powf(exponent.max(0.0))).round() as u8
+            }
+            Self::Fixed(v) => v,
+        };
+        // A null velocity would turn the note on into a note off:
+        v.clamp(1, 127)
+    }
+}
+
+/// Changes the MIDI messages received by a DSP before it handles them, so that
+/// e.g. several instances can be layered or split without extra MIDI plugins
+///
+/// The stages are applied in the order of the fields. Messages that are not
+/// channel messages (SysEx, clock, etc.) are never changed
+#[derive(Debug, Clone, PartialEq)]
+pub struct MidiTransform {
+    /// The channels (0 to 15) whose messages are kept, as a bit mask (bit N for
+    /// channel N)
+    pub channels: u16,
+    /// Notes outside of this range (before transposition) are dropped
+    pub note_range: RangeInclusive<u8>,
+    /// In semitones. Notes that would end up out of the MIDI range are dropped
+    pub transpose: i8,
+    pub velocity_curve: VelocityCurve,
+    /// Pairs of CC numbers `(from, to)`: CC `from` is received as CC `to`
+    pub cc_remaps: Vec<(u8, u8)>,
+}
+
+impl Default for MidiTransform {
+    /// Keeps all the messages unchanged
+    fn default() -> Self {
+        Self {
+            channels: 0xFFFF,
+            note_range: 0..=127,
+            transpose: 0,
+            velocity_curve: VelocityCurve::Linear,
+            cc_remaps: vec![],
+        }
+    }
+}
+
+impl MidiTransform {
+    fn accepts_channel(&self, channel: u8) -> bool {
+        self.channels & (1 << (channel & 0x0F)) != 0
+    }
+
+    /// What a note on becomes, or None if it is dropped
+    pub(crate) fn map_note(&self, channel: u8, note: u8) -> Option<u8> {
+        if !self.accepts_channel(channel) || !self.note_range.contains(&note) {
+            return None;
+        }
+        u8::try_from(note as i32 + self.transpose as i32)
+            .ok()
+            .filter(|&n| n < 128)
+    }
+}
+
+/// A [`MidiTransform`] and what it needs to remember between two messages
+pub(crate) struct MidiTransformState {
+    pub(crate) transform: MidiTransform,
+    /// For each channel and note of the note ons that were let through, the
+    /// note they became. Note offs and polyphonic aftertouch follow their note
+    /// on, even if the transform changed in-between
+    held: [[Option<u8>; 128]; 16],
+    /// For each channel and key, the note that its last note on became. Unlike
+    /// `held`, kept after the note off, as the voice may still be releasing
+    sounding: [[Option<u8>; 128]; 16],
+}
+
+impl Default for MidiTransformState {
+    fn default() -> Self {
+        Self {
+            transform: MidiTransform::default(),
+            held: [[None; 128]; 16],
+            sounding: [[None; 128]; 16],
+        }
+    }
+}
+
+impl std::fmt::Debug for MidiTransformState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MidiTransformState")
+            .field("transform", &self.transform)
+            .finish_non_exhaustive()
+    }
+}
+
+impl MidiTransformState {
+    /// The note played by the voices that a key started, if that key was let
+    /// through
+    pub(crate) fn note_of_key(&self, channel: u8, key: u8) -> Option<u8> {
+        self.sounding[(channel & 0x0F) as usize][(key & 0x7F) as usize]
+    }
+
+    /// The key that started the voices playing a note, if any. The opposite of
+    /// [`Self::note_of_key`]
+    pub(crate) fn key_of_note(&self, channel: u8, note: u8) -> Option<u8> {
+        self.sounding[(channel & 0x0F) as usize]
+            .iter()
+            .rposition(|&n| n == Some(note))
+            .map(|key| key as u8)
+    }
+
+    /// Calls `f` on the message(s) that `msg` (a complete message, with its
+    /// status byte) becomes. Does not allocate
+    pub(crate) fn apply(&mut self, msg: &[u8], mut f: impl FnMut(&[u8])) {
+        let status = msg[0];
+        if !(0x80..=0xEF).contains(&status) || msg.len() < 2 {
+            return f(msg);
+        }
+        let channel = status & 0x0F;
+        let key = (msg[1] & 0x7F) as usize;
+        let mut buf = [0; 3];
+        let out = &mut buf[..msg.len()];
+        out.copy_from_slice(msg);
+        match status & 0xF0 {
+            0x90 if msg.len() == 3 && msg[2] > 0 => {
+                let Some(note) = self.transform.map_note(channel, msg[1]) else {
+                    return;
+                };
+                // The same key may be already held, but with a note on that
+                // became another note:
+                if let Some(old_note) = self.held[channel as usize][key].replace(note) {
+                    if old_note != note {
+                        f(&[0x80 | channel, old_note, 0]);
+                    }
+                }
+                self.sounding[channel as usize][key] = Some(note);
+                out[1] = note;
+                out[2] = self.transform.velocity_curve.apply(msg[2]);
+            }
+            0x80 | 0x90 => match self.held[channel as usize][key].take() {
+                Some(note) => out[1] = note,
+                None => return,
+            },
+            0xA0 => match self.held[channel as usize][key] {
+                Some(note) => out[1] = note,
+                None => return,
+            },
+            _ if !self.transform.accepts_channel(channel) => return,
+            0xB0 => {
+                if let Some(&(_, to)) = self.transform.cc_remaps.iter().find(|r| r.0 == msg[1]) {
+                    out[1] = to & 0x7F;
+                }
+            }
+            _ => {}
+        }
+        f(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(state: &mut MidiTransformState, msg: &[u8]) -> Vec<Vec<u8>> {
+        let mut out = vec![];
+        state.apply(msg, |m| out.push(m.to_vec()));
+        out
+    }
+
+    #[test]
+    fn channel_mask_and_note_range() {
+        let mut state = MidiTransformState::default();
+        state.transform.channels = 1 << 2;
+        state.transform.note_range = 48..=72;
+        assert_eq!(
+            apply(&mut state, &[0x92, 60, 100]),
+            vec![vec![0x92, 60, 100]]
+        );
+        // Wrong channel:
+        assert!(apply(&mut state, &[0x93, 60, 100]).is_empty());
+        assert!(apply(&mut state, &[0xB3, 1, 64]).is_empty());
+        // Out of the range:
+        assert!(apply(&mut state, &[0x92, 47, 100]).is_empty());
+        assert!(apply(&mut state, &[0x92, 73, 100]).is_empty());
+        // The note offs of dropped notes are dropped too:
+        assert!(apply(&mut state, &[0x82, 73, 0]).is_empty());
+        assert_eq!(apply(&mut state, &[0x82, 60, 0]), vec![vec![0x82, 60, 0]]);
+        // Not a channel message:
+        assert_eq!(apply(&mut state, &[0xF8]), vec![vec![0xF8]]);
+    }
+
+    #[test]
+    fn transposed_notes_out_of_the_midi_range_are_dropped() {
+        let mut transform = MidiTransform {
+            transpose: 12,
+            ..Default::default()
+        };
+        assert_eq!(transform.map_note(0, 115), Some(127));
+        assert_eq!(transform.map_note(0, 116), None);
+        transform.transpose = -12;
+        assert_eq!(transform.map_note(0, 12), Some(0));
+        assert_eq!(transform.map_note(0, 11), None);
+    }
+
+    #[test]
+    fn velocity_curves() {
+        assert_eq!(VelocityCurve::Linear.apply(90), 90);
+        assert_eq!(VelocityCurve::Power(2.0).apply(127), 127);
+        assert_eq!(VelocityCurve::Power(2.0).apply(64), 32);
+        assert_eq!(VelocityCurve::Power(0.5).apply(32), 64);
+        assert_eq!(VelocityCurve::Fixed(100).apply(3), 100);
+        // Never turns a note on into a note off:
+        assert_eq!(VelocityCurve::Power(4.0).apply(5), 1);
+        assert_eq!(VelocityCurve::Fixed(0).apply(64), 1);
+    }
+
+    #[test]
+    fn note_offs_release_the_note_their_note_on_became() {
+        let mut state = MidiTransformState::default();
+        state.transform.transpose = 7;
+        assert_eq!(
+            apply(&mut state, &[0x90, 60, 100]),
+            vec![vec![0x90, 67, 100]]
+        );
+        state.transform.transpose = -5;
+        assert_eq!(apply(&mut state, &[0xA0, 60, 30]), vec![vec![0xA0, 67, 30]]);
+        assert_eq!(apply(&mut state, &[0x90, 60, 0]), vec![vec![0x90, 67, 0]]);
+        assert_eq!(state.note_of_key(0, 60), Some(67));
+        assert_eq!(state.key_of_note(0, 67), Some(60));
+        // Already released:
+        assert!(apply(&mut state, &[0x80, 60, 0]).is_empty());
+    }
+
+    #[test]
+    fn retriggering_a_key_releases_the_note_it_played_before() {
+        let mut state = MidiTransformState::default();
+        assert_eq!(
+            apply(&mut state, &[0x90, 60, 100]),
+            vec![vec![0x90, 60, 100]]
+        );
+        state.transform.transpose = 2;
+        assert_eq!(
+            apply(&mut state, &[0x90, 60, 100]),
+            vec![vec![0x80, 60, 0], vec![0x90, 62, 100]]
+        );
+        assert_eq!(apply(&mut state, &[0x80, 60, 0]), vec![vec![0x80, 62, 0]]);
+    }
+
+    #[test]
+    fn cc_remaps() {
+        let mut state = MidiTransformState::default();
+        state.transform.cc_remaps = vec![(1, 74)];
+        assert_eq!(apply(&mut state, &[0xB0, 1, 64]), vec![vec![0xB0, 74, 64]]);
+        assert_eq!(apply(&mut state, &[0xB0, 2, 64]), vec![vec![0xB0, 2, 64]]);
+    }
+}
@@ -0,0 +1,203 @@
+use crate::wrapper::*;
+
+/// How the voices of an instrument are allocated to new notes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VoicePolicy {
+    /// When all voices are used, steal the voice that started first
+    /// (preferably among the released ones). This is Faust's default
+    #[default]
+    StealOldest,
+    /// When all voices are used, steal the quietest voice (preferably among
+    /// the released ones)
+    StealQuietest,
+    /// When all voices are used, steal the voice playing the lowest note
+    /// (preferably among the released ones)
+    StealLowest,
+    /// When all voices are used, steal the voice playing the highest note
+    /// (preferably among the released ones)
+    StealHighest,
+    /// Only one voice plays, and a new note only changes its pitch if it was
+    /// playing. Releasing a note goes back to the last note still held
+    MonoLegato,
+    /// Only one voice plays, and a new note always restarts it. Releasing a
+    /// note goes back to the last note still held
+    MonoRetrigger,
+}
+
+impl VoicePolicy {
+    pub(crate) fn to_w_policy(self) -> WVoicePolicy {
+        match self {
+            Self::StealOldest => WVoicePolicy::STEAL_OLDEST,
+            Self::StealQuietest => WVoicePolicy::STEAL_QUIETEST,
+            Self::StealLowest => WVoicePolicy::STEAL_LOWEST,
+            Self::StealHighest => WVoicePolicy::STEAL_HIGHEST,
+            Self::MonoLegato => WVoicePolicy::MONO_LEGATO,
+            Self::MonoRetrigger => WVoicePolicy::MONO_RETRIGGER,
+        }
+    }
+}
+
+/// What happens to the voice of a note when that note is released
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VoiceRelease {
+    /// The voice plays the release of its envelope, and becomes free only once
+    /// it is silent. This is Faust's default
+    #[default]
+    Release,
+    /// The voice is silenced and becomes free immediately, so that released
+    /// notes never need to be stolen
+    Cut,
+}
+
+/// Which MPE zone the controller uses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MpeZone {
+    /// Master channel 1, member channels from 2 upwards
+    Lower,
+    /// Master channel 16, member channels from 15 downwards
+    Upper,
+}
+
+/// How an MPE controller uses the MIDI channels
+///
+/// Each note is sent on its own member channel, so the pitch bend, channel
+/// pressure and CC74 (timbre) of that channel apply only to the voice playing
+/// that note:
+///
+/// - pitch bend changes the voice's `freq` widgets
+/// - channel pressure is sent to the widgets with an `[mpe:pressure]` metadata
+/// - CC74 is sent to the widgets with an `[mpe:timbre]` metadata
+///
+/// Pitch bend on the master channel applies to all voices. Other messages on
+/// the master channel are handled as usual
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MpeConfig {
+    pub zone: MpeZone,
+    /// How many member channels the zone has (from 1 to 15)
+    pub member_channels: u8,
+    /// The pitch bend range of the member channels, in semitones
+    pub pitch_bend_range: f32,
+    /// The pitch bend range of the master channel, in semitones
+    pub master_pitch_bend_range: f32,
+}
+
+impl Default for MpeConfig {
+    /// The default settings of the MPE specification
+    fn default() -> Self {
+        Self {
+            zone: MpeZone::Lower,
+            member_channels: 15,
+            pitch_bend_range: 48.0,
+            master_pitch_bend_range: 2.0,
+        }
+    }
+}
+
+impl MpeConfig {
+    pub(crate) fn to_w_config(self) -> WMpeConfig {
+        WMpeConfig {
+            lower_zone: self.zone == MpeZone::Lower,
+            member_channels: self.member_channels.clamp(1, 15) as i32,
+            pitch_bend_range: self.pitch_bend_range,
+            master_pitch_bend_range: self.master_pitch_bend_range,
+        }
+    }
+}
+
+/// Identifies the voice(s) playing a note
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NoteRef {
+    /// The id given to the note when it started (see
+    /// [`crate::SingletonDsp::handle_note_on`]). If None, the voices are
+    /// identified by their channel and note
+    pub note_id: Option<i32>,
+    /// From 0 to 15
+    pub channel: u8,
+    pub note: u8,
+}
+
+impl NoteRef {
+    pub(crate) fn to_w_note_ref(self) -> WNoteRef {
+        WNoteRef {
+            note_id: self.note_id.unwrap_or(-1),
+            channel: self.channel as i32,
+            pitch: self.note as i32,
+        }
+    }
+
+    pub(crate) fn from_w_note_ref(note: &WNoteRef) -> Self {
+        Self {
+            note_id: (note.note_id >= 0).then_some(note.note_id),
+            channel: note.channel as u8,
+            note: note.pitch as u8,
+        }
+    }
+}
+
+/// A change of timbre of a single note while it is playing (like the CLAP note
+/// expressions)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoteExpression {
+    /// From 0 to 1, sent to the widgets with an `[mpe:pressure]` metadata
+    Pressure,
+    /// From 0 to 1, sent to the widgets with an `[mpe:timbre]` metadata
+    Timbre,
+    /// From 0 (left) to 1 (right), sent to the widgets with an `[mpe:pan]`
+    /// metadata
+    Pan,
+    /// From 0 to 1, sent to the widgets with an `[mpe:vibrato]` metadata
+    Vibrato,
+    /// From 0 to 1, sent to the widgets with an `[mpe:expression]` metadata
+    Expression,
+    /// Multiplies the gain given by the velocity of the note (1 = unchanged)
+    Volume,
+    /// Offsets the frequency of the note, in semitones
+    Tuning,
+}
+
+impl NoteExpression {
+    pub(crate) fn to_w_expression(self) -> WNoteExpression {
+        match self {
+            Self::Pressure => WNoteExpression::NOTE_PRESSURE,
+            Self::Timbre => WNoteExpression::NOTE_TIMBRE,
+            Self::Pan => WNoteExpression::NOTE_PAN,
+            Self::Vibrato => WNoteExpression::NOTE_VIBRATO,
+            Self::Expression => WNoteExpression::NOTE_EXPRESSION,
+            Self::Volume => WNoteExpression::NOTE_VOLUME,
+            Self::Tuning => WNoteExpression::NOTE_TUNING,
+        }
+    }
+}
+
+/// What a voice of an instrument was doing at the end of the last computed
+/// buffer
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VoiceState {
+    /// The MIDI note the voice plays
+    pub note: u8,
+    /// The velocity of that note
+    pub velocity: u8,
+    /// How long ago the note started
+    pub age: std::time::Duration,
+    /// Whether the note has been released (ie. the voice is finishing its
+    /// release before becoming free)
+    pub releasing: bool,
+}
+
+impl VoiceState {
+    pub(crate) fn from_w_voice_state(state: &WVoiceState, sample_rate: i32) -> Option<Self> {
+        let releasing = match state.activity {
+            WVoiceActivity::VOICE_FREE => return None,
+            WVoiceActivity::VOICE_PLAYING => false,
+            WVoiceActivity::VOICE_RELEASING => true,
+        };
+        Some(Self {
+            note: state.pitch as u8,
+            velocity: state.velocity as u8,
+            age: std::time::Duration::from_secs_f64(
+                state.age.max(0) as f64 / sample_rate.max(1) as f64,
+            ),
+            releasing,
+        })
+    }
+}
@@ -0,0 +1,80 @@
+use crate::HostValue;
+
+/// The state of the host's transport at the beginning of the next buffer
+///
+/// See [`crate::SingletonDsp::handle_transport`]
+pub struct TransportData {
+    /// Whether the transport is playing
+    pub playing: bool,
+    /// The tempo (in beats per minute)
+    pub tempo: Option<f64>,
+    /// Where we are in the track (in quarter notes)
+    pub pos_beats: Option<f64>,
+    /// The number of the current bar
+    pub bar_number: Option<i32>,
+    /// Where the current bar started in the track (in quarter notes)
+    pub bar_start_pos_beats: Option<f64>,
+    /// The time signature as (numerator, denominator)
+    pub time_sig: Option<(i32, i32)>,
+    /// The buffer size
+    pub next_buffer_size: usize,
+}
+
+impl TransportData {
+    /// Calls `f` with the value to give to a [`HostValue`] at the start of the
+    /// buffer, and then once each time this value reaches a new integer (ie.
+    /// on each new beat or bar) within the buffer, with the offset of the
+    /// corresponding sample
+    pub(crate) fn host_value_changes(
+        &self,
+        sample_rate: f64,
+        host_value: HostValue,
+        mut f: impl FnMut(usize, f64),
+    ) {
+        let beats_per_sample = match (self.playing, self.tempo) {
+            (true, Some(tempo)) => tempo / 60.0 / sample_rate,
+            _ => 0.0,
+        };
+        let (start_value, per_sample) = match host_value {
+            HostValue::Playing => (self.playing as i32 as f64, 0.0),
+            HostValue::Bpm => match self.tempo {
+                Some(tempo) => (tempo, 0.0),
+                None => return,
+            },
+            HostValue::Beat => match self.pos_beats {
+                Some(pos) => (pos, beats_per_sample),
+                None => return,
+            },
+            HostValue::Bar => match (
+                self.pos_beats,
+                self.bar_number,
+                self.bar_start_pos_beats,
+                self.time_sig,
+            ) {
+                (Some(pos), Some(bar), Some(bar_start), Some((num, denom))) => {
+                    // The time signature gives the length of a bar in
+                    // 1/denom notes, and we want it in quarter notes:
+                    let beats_per_bar = num as f64 * 4.0 / denom as f64;
+                    (
+                        bar as f64 + (pos - bar_start) / beats_per_bar,
+                        beats_per_sample / beats_per_bar,
+                    )
+                }
+                _ => return,
+            },
+        };
+
+        f(0, start_value);
+        if per_sample > 0.0 {
+            let mut next_integer = start_value.floor() + 1.0;
+            loop {
+                let offset = ((next_integer - start_value) / per_sample).ceil() as usize;
+                if offset >= self.next_buffer_size {
+                    break;
+                }
+                f(offset, next_integer);
+                next_integer += 1.0;
+            }
+        }
+    }
+}
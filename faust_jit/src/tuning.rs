@@ -0,0 +1,302 @@
+use std::path::Path;
+
+/// The frequency of each MIDI note, given to the `freq` widgets of the voices
+/// of an instrument instead of the standard 12-TET frequencies
+///
+/// A tuning can be read from Scala files (a `.scl` scale and an optional `.kbm`
+/// keyboard mapping), or be given directly as a table (eg. one obtained from an
+/// MTS-ESP master)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tuning {
+    freqs: [f64; 128],
+}
+
+impl Default for Tuning {
+    /// Standard 12-TET tuning, with A4 (note 69) at 440Hz
+    fn default() -> Self {
+        Self::from_freqs(std::array::from_fn(|note| {
+            440.0 * 2f64.powf((note as f64 - 69.0) / 12.0)
+        }))
+    }
+}
+
+/// A Scala keyboard mapping (see <https://www.huygens-fokker.org/scala/help.htm#mappings>)
+struct KeyboardMapping {
+    /// The first and last notes to retune. The other ones keep their 12-TET
+    /// frequency
+    first_note: i32,
+    last_note: i32,
+    /// The note that corresponds to scale degree 0
+    middle_note: i32,
+    /// The note that has the reference frequency
+    reference_note: i32,
+    reference_freq: f64,
+    /// The scale degree at which the mapping repeats
+    octave_degree: i32,
+    /// The scale degree of each key (from middle_note onwards), None for keys
+    /// that are not mapped (which keep their 12-TET frequency). Empty means
+    /// that each key is mapped to the next scale degree
+    map: Vec<Option<i32>>,
+}
+
+impl Default for KeyboardMapping {
+    fn default() -> Self {
+        Self {
+            first_note: 0,
+            last_note: 127,
+            middle_note: 60,
+            reference_note: 69,
+            reference_freq: 440.0,
+            octave_degree: 0,
+            map: vec![],
+        }
+    }
+}
+
+/// The lines of a Scala file that are not comments
+fn scala_lines(contents: &str) -> impl Iterator<Item = &str> {
+    contents
+        .lines()
+        .filter(|line| !line.starts_with('!'))
+        .map(|line| line.trim())
+}
+
+/// Reads a `.scl` file. Returns the size in cents of each scale degree (from
+/// degree 1 to the last one, which is the period of the scale)
+fn parse_scl(contents: &str) -> Result<Vec<f64>, String> {
+    let mut lines = scala_lines(contents).skip(1); // The first line is a description
+    let num_notes: usize = lines
+        .next()
+        .and_then(|line| line.split_whitespace().next())
+        .ok_or("Missing number of notes")?
+        .parse()
+        .map_err(|e| format!("Invalid number of notes: {}", e))?;
+    if num_notes == 0 {
+        // The last degree is the period of the scale, so there must be one:
+        return Err("The scale must have at least one note (its period)".to_string());
+    }
+    let degrees = lines
+        .take(num_notes)
+        .map(|line| {
+            let pitch = line.split_whitespace().next().unwrap_or("");
+            let cents = if pitch.contains('.') {
+                pitch.parse::<f64>().ok()
+            } else {
+                let (num, denom) = pitch.split_once('/').unwrap_or((pitch, "1"));
+                match (num.parse::<f64>(), denom.parse::<f64>()) {
+                    (Ok(num), Ok(denom)) if num > 0.0 && denom > 0.0 => {
+                        Some(1200.0 * (num / denom).log2())
+                    }
+                    _ => None,
+                }
+            };
+            cents.ok_or(format!("Invalid pitch: '{}'", line))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if degrees.len() != num_notes {
+        return Err(format!(
+            "Expected {} pitches, found {}",
+            num_notes,
+            degrees.len()
+        ));
+    }
+    Ok(degrees)
+}
+
+/// Reads a `.kbm` file
+fn parse_kbm(contents: &str) -> Result<KeyboardMapping, String> {
+    let mut lines = scala_lines(contents).filter(|line| !line.is_empty());
+    let mut next_field = |name| {
+        lines
+            .next()
+            .and_then(|line| line.split_whitespace().next())
+            .ok_or(format!("Missing {}", name))
+    };
+    fn parse<T: std::str::FromStr>(name: &str, s: &str) -> Result<T, String> {
+        s.parse().map_err(|_| format!("Invalid {}: '{}'", name, s))
+    }
+    let map_size: usize = parse("map size", next_field("map size")?)?;
+    let first_note = parse("first note", next_field("first note")?)?;
+    let last_note = parse("last note", next_field("last note")?)?;
+    let middle_note = parse("middle note", next_field("middle note")?)?;
+    let reference_note = parse("reference note", next_field("reference note")?)?;
+    let reference_freq = parse("reference frequency", next_field("reference frequency")?)?;
+    let octave_degree = parse("octave degree", next_field("octave degree")?)?;
+    let map = (0..map_size)
+        .map(|_| match next_field("map entry")? {
+            "x" | "X" => Ok(None),
+            entry => parse("map entry", entry).map(Some),
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(KeyboardMapping {
+        first_note,
+        last_note,
+        middle_note,
+        reference_note,
+        reference_freq,
+        octave_degree,
+        map,
+    })
+}
+
+impl Tuning {
+    /// A tuning given directly by the frequency (in Hz) of each MIDI note
+    pub fn from_freqs(freqs: [f64; 128]) -> Self {
+        Self { freqs }
+    }
+
+    /// Reads a tuning from the contents of a `.scl` file and (optionally) of a
+    /// `.kbm` file. Without keyboard mapping, degree 0 of the scale is on note
+    /// 60 and note 69 is at 440Hz
+    pub fn from_scala(scl: &str, opt_kbm: Option<&str>) -> Result<Self, String> {
+        let degrees = parse_scl(scl).map_err(|e| format!("Invalid .scl file: {}", e))?;
+        let kbm = match opt_kbm {
+            Some(kbm) => parse_kbm(kbm).map_err(|e| format!("Invalid .kbm file: {}", e))?,
+            None => KeyboardMapping::default(),
+        };
+        let period = degrees[degrees.len() - 1];
+        let degree_cents = |degree: i32| {
+            let n = degrees.len() as i32;
+            let step = degree.rem_euclid(n);
+            let cents = if step == 0 {
+                0.0
+            } else {
+                degrees[step as usize - 1]
+            };
+            degree.div_euclid(n) as f64 * period + cents
+        };
+        // The distance (in cents) between a note and the middle note:
+        let note_cents = |note: i32| {
+            let offset = note - kbm.middle_note;
+            if kbm.map.is_empty() {
+                return Some(degree_cents(offset));
+            }
+            let size = kbm.map.len() as i32;
+            let degree = kbm.map[offset.rem_euclid(size) as usize]?;
+            Some(degree_cents(
+                offset.div_euclid(size) * kbm.octave_degree + degree,
+            ))
+        };
+        let reference_cents = note_cents(kbm.reference_note)
+            .ok_or("The reference note of the keyboard mapping is not mapped")?;
+
+        let mut tuning = Self::default();
+        for note in kbm.first_note.max(0)..=kbm.last_note.min(127) {
+            if let Some(cents) = note_cents(note) {
+                tuning.freqs[note as usize] =
+                    kbm.reference_freq * 2f64.powf((cents - reference_cents) / 1200.0);
+            }
+        }
+        Ok(tuning)
+    }
+
+    /// Reads a tuning from a `.scl` file and (optionally) a `.kbm` file
+    ///
+    /// See [`Self::from_scala`]
+    pub fn from_scala_files(scl_path: &Path, opt_kbm_path: Option<&Path>) -> Result<Self, String> {
+        let read = |path: &Path| {
+            std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))
+        };
+        let kbm = opt_kbm_path.map(read).transpose()?;
+        Self::from_scala(&read(scl_path)?, kbm.as_deref())
+    }
+
+    /// The frequency (in Hz) of each MIDI note
+    pub fn freqs(&self) -> &[f64; 128] {
+        &self.freqs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TWELVE_TET: &str = "! 12tet.scl
+!
+12-TET
+ 12
+!
+ 100.0
+ 200.0
+ 300.0
+ 400.0
+ 500.0
+ 600.0
+ 700.0
+ 800.0
+ 900.0
+ 1000.0
+ 1100.0
+ 2/1
+";
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn twelve_tet_scale_gives_the_default_tuning() {
+        let tuning = Tuning::from_scala(TWELVE_TET, None).unwrap();
+        for (a, b) in tuning.freqs().iter().zip(Tuning::default().freqs()) {
+            assert_close(*a, *b);
+        }
+        assert_close(tuning.freqs()[69], 440.0);
+    }
+
+    #[test]
+    fn reads_ratios_and_cents() {
+        let degrees = parse_scl("Mixed\n3\n150.0 a comment\n3/2\n2\n").unwrap();
+        assert_eq!(degrees.len(), 3);
+        assert_close(degrees[0], 150.0);
+        assert_close(degrees[1], 1200.0 * 1.5f64.log2());
+        assert_close(degrees[2], 1200.0);
+    }
+
+    #[test]
+    fn skips_comments() {
+        let degrees =
+            parse_scl("! name.scl\n!\nDescription\n! count:\n2\n! first\n700.0\n!\n2/1\n").unwrap();
+        assert_eq!(degrees.len(), 2);
+        assert_close(degrees[0], 700.0);
+        assert_close(degrees[1], 1200.0);
+    }
+
+    #[test]
+    fn unmapped_keys_keep_their_12_tet_frequency() {
+        let kbm = "! Every key but C# mapped, with A4 at 432Hz
+12
+0
+127
+60
+69
+432.0
+12
+! Mapping
+0
+x
+2
+3
+4
+5
+6
+7
+8
+9
+10
+11
+";
+        let tuning = Tuning::from_scala(TWELVE_TET, Some(kbm)).unwrap();
+        let default = Tuning::default();
+        assert_close(tuning.freqs()[69], 432.0);
+        assert_close(tuning.freqs()[60], 432.0 * 2f64.powf(-9.0 / 12.0));
+        assert_close(tuning.freqs()[72], 432.0 * 2f64.powf(3.0 / 12.0));
+        assert_close(tuning.freqs()[61], default.freqs()[61]);
+        assert_close(tuning.freqs()[73], default.freqs()[73]);
+    }
+
+    #[test]
+    fn empty_scale_is_an_error() {
+        let err = Tuning::from_scala("Empty\n0\n", None).unwrap_err();
+        assert!(err.contains("at least one note"), "{}", err);
+    }
+}
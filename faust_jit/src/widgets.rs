@@ -1,4 +1,4 @@
-use super::wrapper::*;
+use super::{midi::*, wrapper::*};
 use std::{
     collections::{HashMap, VecDeque},
     ffi::{c_char, c_void, CStr},
@@ -174,6 +174,33 @@ pub enum DspWidget<Z> {
     // },
 }
 
+/// The full path of a widget (see [`crate::ParamRef::Path`]), given the full
+/// path of the box containing it ("" at the top level) and its label. Widgets
+/// with an empty label (eg. the boxes Faust adds implicitly) have the same path
+/// as their box
+pub fn widget_path(box_path: &str, label: &str) -> String {
+    if label.is_empty() {
+        box_path.to_owned()
+    } else {
+        format!("{}/{}", box_path, label)
+    }
+}
+
+/// Calls `f` on each widget of a tree, along with its full path (see
+/// [`widget_path`]). Boxes are given before the widgets they contain
+pub fn for_each_widget<Z>(widgets: &[DspWidget<Z>], mut f: impl FnMut(&str, &DspWidget<Z>)) {
+    fn rec<Z>(widgets: &[DspWidget<Z>], box_path: &str, f: &mut impl FnMut(&str, &DspWidget<Z>)) {
+        for widget in widgets {
+            let path = widget_path(box_path, widget.label());
+            f(&path, widget);
+            if let DspWidget::Box { inner, .. } = widget {
+                rec(inner, &path, f);
+            }
+        }
+    }
+    rec(widgets, "", &mut f)
+}
+
 impl<Z> DspWidget<Z> {
     pub fn label(&self) -> &str {
         match self {
@@ -185,6 +212,99 @@ impl<Z> DspWidget<Z> {
     }
 }
 
+/// The widgets of an instrument loaded with ungrouped voices (see
+/// [`crate::DspLoadMode::Instrument`]), which Faust puts in a "Polyphonic" tab
+pub struct PolyWidgets<'a, Z> {
+    /// The box of the widgets that control all the voices at once (along with
+    /// a "Panic" button that stops all voices)
+    pub all_voices: &'a mut DspWidget<Z>,
+    /// The box of the widgets of each voice, in voice order
+    pub voices: Vec<&'a mut DspWidget<Z>>,
+}
+
+impl<'a, Z> PolyWidgets<'a, Z> {
+    /// Looks for the widgets of the voices in a widget tree. Returns None if
+    /// the DSP is not an instrument, has only one voice, or was loaded with
+    /// grouped voices
+    pub fn find(widgets: &'a mut [DspWidget<Z>]) -> Option<Self> {
+        for widget in widgets {
+            if let DspWidget::Box {
+                layout,
+                label,
+                inner,
+            } = widget
+            {
+                // Faust gives the "Polyphonic" tab a "Voices" box first and
+                // then (only if the voices are not grouped) one box per voice:
+                let is_poly_tab = matches!(layout, BoxLayout::Tab { .. })
+                    && label == "Polyphonic"
+                    && inner.len() > 1
+                    && inner[0].label() == "Voices";
+                if is_poly_tab {
+                    let (all_voices, voices) = inner.split_first_mut().unwrap();
+                    return Some(Self {
+                        all_voices,
+                        voices: voices.iter_mut().collect(),
+                    });
+                }
+                // The poly DSP may be wrapped in a box (eg. when the script
+                // also has an effect part):
+                if let Some(found) = Self::find(inner) {
+                    return Some(found);
+                }
+            }
+        }
+        None
+    }
+}
+
+impl<Z: Zone> PolyWidgets<'_, Z> {
+    /// For each zone of the widgets controlling all the voices, the zone of
+    /// the same widget in each voice
+    pub(crate) fn voice_zones(&self) -> HashMap<ZonePtr, Vec<ZonePtr>> {
+        fn rec<Z: Zone>(
+            all: &[DspWidget<Z>],
+            voice: &[DspWidget<Z>],
+            (index, nvoices): (usize, usize),
+            zones: &mut HashMap<ZonePtr, Vec<ZonePtr>>,
+        ) {
+            for pair in all.iter().zip(voice) {
+                match pair {
+                    (DspWidget::Box { inner: all, .. }, DspWidget::Box { inner: voice, .. }) => {
+                        rec(all, voice, (index, nvoices), zones)
+                    }
+                    (
+                        DspWidget::BoolParam { zone: all, .. },
+                        DspWidget::BoolParam { zone: voice, .. },
+                    )
+                    | (
+                        DspWidget::NumParam { zone: all, .. },
+                        DspWidget::NumParam { zone: voice, .. },
+                    ) => {
+                        zones
+                            .entry(ZonePtr(all.as_ptr()))
+                            .or_insert_with(|| vec![ZonePtr(std::ptr::null_mut()); nvoices])
+                            [index] = ZonePtr(voice.as_ptr())
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let mut zones = HashMap::new();
+        let DspWidget::Box { inner: all, .. } = &*self.all_voices else {
+            return zones;
+        };
+        for (index, voice) in self.voices.iter().enumerate() {
+            if let DspWidget::Box { inner: voice, .. } = &**voice {
+                // The box of all the voices starts with its "Panic" button:
+                let all = &all[all.len().saturating_sub(voice.len())..];
+                rec(all, voice, (index, self.voices.len()), &mut zones);
+            }
+        }
+        zones
+    }
+}
+
 #[derive(Debug, PartialEq)]
 /// A list of (label,value) pairs for [`NumParamStyle::Menu`] and
 /// [`NumParamStyle::Radio`] styles
@@ -238,12 +358,28 @@ enum WidgetStyle {
     Disp(NumDisplayStyle),
 }
 
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+/// A value coming from the host's transport, that a widget can be bound to
+/// with a `[host:xxx]` metadata
+pub enum HostValue {
+    /// The tempo (`[host:bpm]`)
+    Bpm,
+    /// The position in the track, in quarter notes (`[host:beat]`)
+    Beat,
+    /// The position in the track, in bars (`[host:bar]`)
+    Bar,
+    /// 1 if the transport is playing, 0 if not (`[host:playing]`)
+    Playing,
+}
+
 enum MetadataElem {
     Style(WidgetStyle),
     Scale(WidgetScale),
     Hidden(bool),
     Unit(String),
     Tooltip(String),
+    Host(HostValue),
+    HiResCtrl(HiResCtrlNum, u8),
 }
 
 pub(crate) struct DspWidgetsBuilder {
@@ -251,11 +387,38 @@ pub(crate) struct DspWidgetsBuilder {
     metadata_map: HashMap<*mut f32, Vec<MetadataElem>>,
 }
 
+/// A raw zone pointer that can be kept outside of the widgets. Like the zones
+/// in the widgets, it is only valid as long as the DSP it comes from is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct ZonePtr(pub(crate) *mut f32);
+unsafe impl Sync for ZonePtr {}
+unsafe impl Send for ZonePtr {}
+
+/// What the [`DspWidgetsBuilder`] gathered about the zones, in addition to the
+/// widgets themselves
+#[derive(Debug, Default)]
+pub(crate) struct DspZones {
+    /// The zones of all the (non-box) widgets, indexed by their full path
+    pub(crate) by_path: HashMap<String, ZonePtr>,
+    /// The zones that should receive some value from the host's transport
+    pub(crate) host: Vec<(HostValue, ZonePtr)>,
+    /// The zones driven by 14-bit MIDI controllers
+    pub(crate) hi_res_ctrls: Vec<HiResCtrl>,
+    /// For each zone of the widgets controlling all the voices of an
+    /// instrument, the zone of the same widget in each voice (see
+    /// [`PolyWidgets`])
+    pub(crate) voices: HashMap<ZonePtr, Vec<ZonePtr>>,
+}
+
 /// A memory zone corresponding to some parameter's current value
 pub trait Zone {
     unsafe fn from_zone_ptr(ptr: *mut f32) -> Self;
 
     fn cur_value(&self) -> f32;
+
+    /// The raw pointer to the zone, which can be used to identify it (see
+    /// [`crate::ParamRef::Zone`])
+    fn as_ptr(&self) -> *mut f32;
 }
 
 impl<'a> Zone for &'a mut f32 {
@@ -266,6 +429,10 @@ impl<'a> Zone for &'a mut f32 {
     fn cur_value(&self) -> f32 {
         **self
     }
+
+    fn as_ptr(&self) -> *mut f32 {
+        *self as *const f32 as *mut f32
+    }
 }
 
 impl DspWidgetsBuilder {
@@ -278,15 +445,25 @@ impl DspWidgetsBuilder {
 
     /// To be called _after_ faust's buildUserInterface has finished, ie. after
     /// w_createUIs has finished. 'a is the lifetime of the DSP itself
-    pub(crate) fn build_widgets<Z: Zone>(mut self, widget_list: &mut Vec<DspWidget<Z>>) {
-        self.build_widgets_rec(widget_list);
+    pub(crate) fn build_widgets<Z: Zone>(
+        mut self,
+        widget_list: &mut Vec<DspWidget<Z>>,
+    ) -> DspZones {
+        let mut zones = DspZones::default();
+        self.build_widgets_rec(widget_list, "", &mut zones);
         assert!(
             self.widget_decls.is_empty(),
             "Some widget declarations haven't been consumed"
         );
+        zones
     }
 
-    fn build_widgets_rec<Z: Zone>(&mut self, cur_level: &mut Vec<DspWidget<Z>>) {
+    fn build_widgets_rec<Z: Zone>(
+        &mut self,
+        cur_level: &mut Vec<DspWidget<Z>>,
+        cur_path: &str,
+        zones: &mut DspZones,
+    ) {
         use MetadataElem as ME;
         use WWidgetDeclType as W;
         let mut empty_vec = Vec::new();
@@ -297,6 +474,8 @@ impl DspWidgetsBuilder {
                 .get_mut(&decl.zone)
                 .unwrap_or(&mut empty_vec);
             let mut style = None;
+            let mut host_value = None;
+            let mut hi_res_ctrls = vec![];
             let mut metadata = NumMetadata {
                 unit: None,
                 scale: WidgetScale::Lin,
@@ -310,6 +489,8 @@ impl DspWidgetsBuilder {
                     ME::Hidden(h) => metadata.hidden = h,
                     ME::Unit(u) => metadata.unit = Some(u),
                     ME::Tooltip(t) => metadata.tooltip = Some(t),
+                    ME::Host(h) => host_value = Some(h),
+                    ME::HiResCtrl(num, channel) => hi_res_ctrls.push((num, channel)),
                 }
             }
 
@@ -354,9 +535,28 @@ impl DspWidgetsBuilder {
                     metadata,
                 },
             };
+            let path = widget_path(cur_path, widget.label());
             if let DspWidget::Box { inner, .. } = &mut widget {
                 // We recurse, so as to add to the newly opened box:
-                self.build_widgets_rec(inner);
+                self.build_widgets_rec(inner, &path, zones);
+            } else {
+                zones.by_path.insert(path, ZonePtr(decl.zone));
+                if let Some(h) = host_value {
+                    zones.host.push((h, ZonePtr(decl.zone)));
+                }
+                let (min, max) = match decl.typ {
+                    W::BUTTON | W::CHECK_BUTTON => (0.0, 1.0),
+                    _ => (decl.min, decl.max),
+                };
+                for (num, channel) in hi_res_ctrls {
+                    zones.hi_res_ctrls.push(HiResCtrl {
+                        num,
+                        channel,
+                        zone: ZonePtr(decl.zone),
+                        min,
+                        max,
+                    });
+                }
             }
             cur_level.push(widget);
         }
@@ -440,6 +640,16 @@ extern "C" fn rs_declare_metadata(
             "1" => Some(ME::Hidden(true)),
             _ => None,
         },
+        "host" => match value {
+            "bpm" => Some(ME::Host(HostValue::Bpm)),
+            "beat" => Some(ME::Host(HostValue::Beat)),
+            "bar" => Some(ME::Host(HostValue::Bar)),
+            "playing" => Some(ME::Host(HostValue::Playing)),
+            _ => None,
+        },
+        // Faust itself deals with the 7-bit part of the controllers, so we
+        // just need to look for those that can have 14-bit values:
+        "midi" => parse_hi_res_ctrl(value).map(|(num, channel)| ME::HiResCtrl(num, channel)),
         _ => None,
     };
     if let Some(elem) = opt_elem {
@@ -451,6 +661,23 @@ extern "C" fn rs_declare_metadata(
     }
 }
 
+/// Parses a `[midi:ctrl N]` (if N < 32) or a `[midi:nrpn N]` metadata, with
+/// an optional channel (`[midi:ctrl N chan]`)
+fn parse_hi_res_ctrl(value: &str) -> Option<(HiResCtrlNum, u8)> {
+    let mut it = value.split_whitespace();
+    let kind = it.next()?;
+    let num: u16 = it.next()?.parse().ok()?;
+    let channel: u8 = match it.next() {
+        Some(c) => c.parse().ok().filter(|c| *c <= 16)?,
+        None => 0,
+    };
+    match kind {
+        "ctrl" if num < 32 => Some((HiResCtrlNum::Cc(num as u8), channel)),
+        "nrpn" if num < 0x3FFF => Some((HiResCtrlNum::Nrpn(num), channel)),
+        _ => None,
+    }
+}
+
 fn parse_metadata_dict(s: &str) -> Option<Vec<(String, f32)>> {
     let trimmed = s.trim();
     let without_brackets = &trimmed[1..trimmed.len() - 1].trim();
@@ -38,8 +38,55 @@ impl<T> DoIf for T {
     }
 }
 
-fn faust_widgets_ui_rec(ui: &mut egui::Ui, widgets: &mut [DspWidget<&mut f32>], in_a_tab: bool) {
+/// Lets the user map MIDI controllers to widgets, by right-clicking a widget
+/// and then moving a controller. To be kept between two frames
+#[derive(Debug, Default)]
+pub struct MidiLearn {
+    /// The widget waiting for a controller: its path and range
+    target: Option<(String, f32, f32)>,
+}
+
+impl MidiLearn {
+    /// The path of the widget waiting for a controller, if any
+    pub fn target(&self) -> Option<&str> {
+        self.target.as_ref().map(|(path, _, _)| path.as_str())
+    }
+
+    pub fn cancel(&mut self) {
+        self.target = None;
+    }
+
+    /// To be called on each frame, with the DSP whose widgets are shown. If a
+    /// widget waits for a controller and one has been moved, returns the
+    /// mapping of that controller to the whole range of the widget
+    pub fn poll(&mut self, dsp: &SingletonDsp) -> Option<CcMapping> {
+        // Always called, so that only controllers moved after the widget was
+        // selected are learned:
+        let (channel, cc) = dsp.take_learned_cc()?;
+        let (path, min, max) = self.target.take()?;
+        Some(CcMapping::new(Some(channel), cc, &path, min, max))
+    }
+
+    /// Adds to a widget a context menu to start learning it
+    fn menu(&mut self, response: &egui::Response, path: &str, min: f32, max: f32) {
+        response.context_menu(|ui| {
+            if ui.button("MIDI learn").clicked() {
+                self.target = Some((path.to_owned(), min, max));
+                ui.close_menu();
+            }
+        });
+    }
+}
+
+fn faust_widgets_ui_rec(
+    ui: &mut egui::Ui,
+    widgets: &mut [DspWidget<&mut f32>],
+    in_a_tab: bool,
+    path: &str,
+    mut learn: Option<&mut MidiLearn>,
+) {
     for w in widgets {
+        let w_path = widget_path(path, w.label());
         match w {
             DspWidget::Box {
                 layout: BoxLayout::Tab { selected },
@@ -63,7 +110,13 @@ fn faust_widgets_ui_rec(ui: &mut egui::Ui, widgets: &mut [DspWidget<&mut f32>],
                     }
                 })
                 .body(|ui| {
-                    faust_widgets_ui_rec(ui, &mut inner[*selected..=*selected], true);
+                    faust_widgets_ui_rec(
+                        ui,
+                        &mut inner[*selected..=*selected],
+                        true,
+                        &w_path,
+                        learn.as_deref_mut(),
+                    );
                 });
             }
             DspWidget::Box {
@@ -77,7 +130,9 @@ fn faust_widgets_ui_rec(ui: &mut egui::Ui, widgets: &mut [DspWidget<&mut f32>],
                     _ => panic!("Cannot be Tab here"),
                 };
                 let mut draw_inner = |ui: &mut egui::Ui| {
-                    ui.with_layout(egui_layout, |ui| faust_widgets_ui_rec(ui, inner, false))
+                    ui.with_layout(egui_layout, |ui| {
+                        faust_widgets_ui_rec(ui, inner, false, &w_path, learn.as_deref_mut())
+                    })
                 };
                 if in_a_tab || label.is_empty() {
                     draw_inner(ui);
@@ -119,6 +174,9 @@ fn faust_widgets_ui_rec(ui: &mut egui::Ui, widgets: &mut [DspWidget<&mut f32>],
                         let mut selected = **zone != 0.0;
                         let resp = ui.checkbox(&mut selected, &*label).interact(Sense::hover());
                         **zone = selected as i32 as f32;
+                        if let Some(learn) = learn.as_deref_mut() {
+                            learn.menu(&resp, &w_path, 0.0, 1.0);
+                        }
                         resp
                     }
                 };
@@ -155,8 +213,11 @@ fn faust_widgets_ui_rec(ui: &mut egui::Ui, widgets: &mut [DspWidget<&mut f32>],
                         if resp.double_clicked() {
                             **zone = *init;
                         }
+                        if let Some(learn) = learn.as_deref_mut() {
+                            learn.menu(&resp, &w_path, *min, *max);
+                        }
                     }
-                    match (layout, style) {
+                    let opt_resp = match (layout, style) {
                         // TODO: NumParamStyle::Knob
                         (_, NumParamStyle::Menu(vals)) => {
                             egui::ComboBox::from_id_source(&*label)
@@ -166,7 +227,8 @@ fn faust_widgets_ui_rec(ui: &mut egui::Ui, widgets: &mut [DspWidget<&mut f32>],
                                         ui.selectable_value(&mut vals.selected, i, k);
                                     }
                                 });
-                            **zone = vals.options[vals.selected].1
+                            **zone = vals.options[vals.selected].1;
+                            None
                         }
                         (layout, NumParamStyle::Radio(vals)) => {
                             let egui_layout = match layout {
@@ -178,16 +240,17 @@ fn faust_widgets_ui_rec(ui: &mut egui::Ui, widgets: &mut [DspWidget<&mut f32>],
                                     ui.radio_value(&mut vals.selected, i, k);
                                 }
                             });
-                            **zone = vals.options[vals.selected].1
+                            **zone = vals.options[vals.selected].1;
+                            None
                         }
-                        (NumParamLayout::NumEntry, _) => {
+                        (NumParamLayout::NumEntry, _) => Some(
                             ui.add(
                                 egui::DragValue::new(*zone)
                                     .clamp_range(rng)
                                     .do_if_some(unit.as_deref(), |s, unit| s.suffix(unit)),
-                            );
-                        }
-                        (layout, _) => {
+                            ),
+                        ),
+                        (layout, _) => Some(
                             ui.add(
                                 egui::Slider::new(*zone, rng)
                                     .step_by(*step as f64)
@@ -196,9 +259,12 @@ fn faust_widgets_ui_rec(ui: &mut egui::Ui, widgets: &mut [DspWidget<&mut f32>],
                                         s.vertical()
                                     })
                                     .do_if(*scale == WidgetScale::Log, |s| s.logarithmic(true)), // TODO: Deal with Exp
-                            );
-                        }
+                            ),
+                        ),
                     };
+                    if let (Some(resp), Some(learn)) = (opt_resp, learn.as_deref_mut()) {
+                        learn.menu(&resp, &w_path, *min, *max);
+                    }
                 });
             }
             DspWidget::NumDisplay {
@@ -352,5 +418,19 @@ fn lerp_colors(min: egui::Color32, max: egui::Color32, t: f32) -> egui::Color32
 
 /// Draw and update the faust widgets inside an egui::Ui
 pub fn faust_widgets_ui(ui: &mut egui::Ui, widgets: &mut [DspWidget<&mut f32>]) {
-    faust_widgets_ui_rec(ui, widgets, false);
+    faust_widgets_ui_rec(ui, widgets, false, "", None);
+}
+
+/// Like [`faust_widgets_ui`], but the parameters can also be right-clicked to
+/// MIDI learn them (see [`MidiLearn`])
+pub fn faust_widgets_ui_with_learn(
+    ui: &mut egui::Ui,
+    widgets: &mut [DspWidget<&mut f32>],
+    learn: &mut MidiLearn,
+) {
+    faust_widgets_ui_rec(ui, widgets, false, "", Some(learn));
+    if learn.target.is_some() {
+        // So that the controller is noticed as soon as it moves:
+        ui.ctx().request_repaint();
+    }
 }
@@ -82,7 +82,13 @@
           installPhase = ''
             mkdir -p $out/bin
             cp target/release/nih_faust_jit_standalone $out/bin
+            cp target/release/faust_jit_compile $out/bin
             cp -R target/bundled $out/plugin
+            # The plugin looks for the helper next to its binary:
+            cp target/release/faust_jit_compile $out/plugin
+            for dir in $out/plugin/*.vst3/Contents/*/; do
+              cp target/release/faust_jit_compile "$dir"
+            done
           '';
         });
 
@@ -0,0 +1,129 @@
+//! Mixing the processed (wet) signal with the unprocessed (dry) one, for the
+//! `mix`, `gain` and `bypass` parameters
+
+use nih_plug::prelude::*;
+
+/// The max latency a DSP can declare, in seconds. The dry signal can't be
+/// delayed more than that
+const MAX_LATENCY_SECS: f32 = 1.0;
+
+/// How long switching the bypass on or off takes, in milliseconds
+const BYPASS_FADE_MS: f32 = 20.0;
+
+/// Keeps the dry signal, delayed by the latency of the DSP so that it stays
+/// aligned with the wet signal
+pub struct DryWet {
+    /// One ring buffer per channel, holding the last input samples
+    delay_lines: Vec<Vec<f32>>,
+    /// Where the next input sample goes in the delay lines
+    write_pos: usize,
+    /// The dry signal of the current buffer, once delayed
+    dry: Vec<Vec<f32>>,
+    /// Goes from 0 (not bypassed) to 1 (bypassed)
+    bypass_fade: Smoother<f32>,
+    /// The last value of the `bypass` parameter, so that the fade restarts
+    /// only when it changes
+    bypassed: bool,
+}
+
+impl Default for DryWet {
+    fn default() -> Self {
+        Self {
+            delay_lines: vec![],
+            write_pos: 0,
+            dry: vec![],
+            bypass_fade: Smoother::new(SmoothingStyle::Linear(BYPASS_FADE_MS)),
+            bypassed: false,
+        }
+    }
+}
+
+impl DryWet {
+    /// Allocates the buffers. Not to be called from the audio thread
+    pub fn resize(&mut self, channels: usize, sample_rate: f32, max_buffer_size: usize) {
+        let max_latency = (sample_rate * MAX_LATENCY_SECS) as usize;
+        self.delay_lines = vec![vec![0.0; max_latency + 1]; channels];
+        self.write_pos = 0;
+        self.dry = vec![vec![0.0; max_buffer_size]; channels];
+    }
+
+    /// The latency that [`Self::mix`] can actually compensate, in samples
+    pub fn max_latency(&self) -> usize {
+        self.delay_lines.first().map_or(0, |line| line.len() - 1)
+    }
+
+    /// Stores the dry signal. To be called before the buffer is processed.
+    /// Does not allocate
+    pub fn store_dry(&mut self, buffer: &Buffer, latency: usize) {
+        let latency = latency.min(self.max_latency());
+        let samples = buffer.samples();
+        for ((input, line), dry) in buffer
+            .as_slice_immutable()
+            .iter()
+            .zip(&mut self.delay_lines)
+            .zip(&mut self.dry)
+        {
+            let size = line.len();
+            let mut pos = self.write_pos;
+            for (&x, d) in input.iter().zip(dry.iter_mut()) {
+                line[pos] = x;
+                *d = line[(pos + size - latency) % size];
+                pos = (pos + 1) % size;
+            }
+        }
+        if let Some(size) = self.delay_lines.first().map(Vec::len) {
+            self.write_pos = (self.write_pos + samples) % size;
+        }
+    }
+
+    /// Crossfades the (processed) buffer with the dry signal stored by
+    /// [`Self::store_dry`], and applies the gain to the result. When bypassed,
+    /// the buffer fades to the dry signal, gain included. Does not allocate
+    pub fn mix(
+        &mut self,
+        buffer: &mut Buffer,
+        mix: &FloatParam,
+        gain: &FloatParam,
+        bypass: bool,
+        sample_rate: f32,
+    ) {
+        self.update_bypass(bypass, sample_rate);
+        for (i, channel_samples) in buffer.iter_samples().enumerate() {
+            let wet = mix.smoothed.next();
+            let gain = gain.smoothed.next();
+            let bypass = self.bypass_fade.next();
+            for (sample, dry) in channel_samples.into_iter().zip(&self.dry) {
+                let dry = dry.get(i).copied().unwrap_or(0.0);
+                let processed = (dry + wet * (*sample - dry)) * gain;
+                *sample = processed + bypass * (dry - processed);
+            }
+        }
+    }
+
+    /// Applies the gain to a buffer when no DSP is loaded, fading it with the
+    /// bypass like [`Self::mix`]. Does not allocate
+    pub fn apply_gain(
+        &mut self,
+        buffer: &mut Buffer,
+        gain: &FloatParam,
+        bypass: bool,
+        sample_rate: f32,
+    ) {
+        self.update_bypass(bypass, sample_rate);
+        for channel_samples in buffer.iter_samples() {
+            let gain = gain.smoothed.next();
+            let gain = gain + self.bypass_fade.next() * (1.0 - gain);
+            for sample in channel_samples {
+                *sample *= gain;
+            }
+        }
+    }
+
+    fn update_bypass(&mut self, bypass: bool, sample_rate: f32) {
+        if bypass != self.bypassed {
+            self.bypass_fade
+                .set_target(sample_rate, if bypass { 1.0 } else { 0.0 });
+            self.bypassed = bypass;
+        }
+    }
+}
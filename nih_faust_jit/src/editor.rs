@@ -2,20 +2,40 @@ use nih_plug::prelude::*;
 use nih_plug_egui::egui;
 use std::sync::{Arc, RwLock};
 
-use crate::{DspState, DspType};
+use crate::{DspState, DspType, LoadStatus, MpeZoneChoice, VelocityCurveChoice, MAX_VOICES};
 
 /// Data shared between the plugin and the GUI thread
 pub(crate) struct EditorArcs {
     pub(crate) nih_egui_state: Arc<nih_plug_egui::EguiState>,
     pub(crate) selected_paths: Arc<RwLock<crate::SelectedPaths>>,
     pub(crate) dsp_state: Arc<RwLock<DspState>>,
+    pub(crate) reload_error: Arc<RwLock<Option<String>>>,
+    pub(crate) load_status: Arc<RwLock<LoadStatus>>,
     pub(crate) dsp_nvoices: Arc<RwLock<i32>>,
+    pub(crate) dsp_voice_policy: Arc<RwLock<crate::VoicePolicyChoice>>,
+    pub(crate) dsp_voice_release: Arc<RwLock<crate::VoiceReleaseChoice>>,
+    pub(crate) dsp_group_voices: Arc<RwLock<bool>>,
+    pub(crate) dsp_mpe: Arc<RwLock<crate::MpeSettings>>,
+    pub(crate) tuning_paths: Arc<RwLock<crate::TuningPaths>>,
+    pub(crate) tuning_error: Arc<RwLock<Option<String>>>,
+    pub(crate) midi_transform: Arc<RwLock<crate::MidiTransformSettings>>,
+    pub(crate) cc_mappings: Arc<RwLock<Vec<crate::CcMappingSettings>>>,
+    pub(crate) slots_state: Arc<crate::SlotsState>,
+    pub(crate) slot_assignment: Arc<RwLock<crate::SlotAssignment>>,
+    pub(crate) host_layout: Arc<RwLock<AudioIOLayout>>,
+    pub(crate) routing: Arc<RwLock<crate::routing::RoutingMatrix>>,
+    pub(crate) swap_fade_ms: Arc<RwLock<f32>>,
+    pub(crate) compile_helper: Arc<RwLock<crate::CompileHelperSettings>>,
 }
 
 /// Data owned only by the GUI thread
 struct EditorState {
     script_dialog: Option<egui_file::FileDialog>,
     lib_path_dialog: Option<egui_file::FileDialog>,
+    scl_dialog: Option<egui_file::FileDialog>,
+    kbm_dialog: Option<egui_file::FileDialog>,
+    helper_dialog: Option<egui_file::FileDialog>,
+    midi_learn: faust_jit_egui::MidiLearn,
 }
 
 impl Default for EditorState {
@@ -23,6 +43,10 @@ impl Default for EditorState {
         Self {
             script_dialog: None,
             lib_path_dialog: None,
+            scl_dialog: None,
+            kbm_dialog: None,
+            helper_dialog: None,
+            midi_learn: faust_jit_egui::MidiLearn::default(),
         }
     }
 }
@@ -52,10 +76,24 @@ pub(crate) fn create_editor(
                             DspState::NoDspScript => {
                                 ui.label("-- No DSP --");
                             }
+                            DspState::Unloaded => {
+                                ui.label("-- DSP unloaded --");
+                            }
                             DspState::Failed(faust_err_msg) => {
                                 ui.colored_label(egui::Color32::LIGHT_RED, faust_err_msg);
                             }
                             DspState::Loaded(dsp) => {
+                                if let Some(err) = &*arcs.reload_error.read().unwrap() {
+                                    ui.colored_label(
+                                        egui::Color32::LIGHT_RED,
+                                        format!(
+                                            "Reload failed, the previous DSP is still running:\n{}",
+                                            err
+                                        ),
+                                    );
+                                }
+                                voice_activity_strip(ui, dsp);
+                                midi_learn_contents(ui, &arcs, dsp, &mut ed_state.midi_learn);
                                 ui.style_mut().wrap = Some(false);
                                 let margin = egui::Margin {
                                     left: 0.0,
@@ -65,7 +103,11 @@ pub(crate) fn create_editor(
                                 };
                                 egui::Frame::default().outer_margin(margin).show(ui, |ui| {
                                     dsp.with_widgets_mut(|widgets| {
-                                        faust_jit_egui::faust_widgets_ui(ui, widgets)
+                                        faust_jit_egui::faust_widgets_ui_with_learn(
+                                            ui,
+                                            widgets,
+                                            &mut ed_state.midi_learn,
+                                        )
                                     })
                                 });
                             }
@@ -77,6 +119,45 @@ pub(crate) fn create_editor(
     )
 }
 
+/// Shows one small square per voice of an instrument, lit when the voice
+/// plays. Hovering a square tells which note the voice plays. Also has a
+/// button to silence all the voices
+fn voice_activity_strip(ui: &mut egui::Ui, dsp: &faust_jit::SingletonDsp) {
+    let states = dsp.voice_states();
+    if states.is_empty() {
+        return;
+    }
+    ui.horizontal(|ui| {
+        if ui
+            .button("Panic")
+            .on_hover_text("Silence all the voices")
+            .clicked()
+        {
+            dsp.all_notes_off(true);
+        }
+        ui.label("Voices:");
+        for state in states {
+            let (rect, response) =
+                ui.allocate_exact_size(egui::vec2(10.0, 10.0), egui::Sense::hover());
+            let color = match &state {
+                None => egui::Color32::DARK_GRAY,
+                Some(state) if state.releasing => egui::Color32::from_rgb(200, 120, 0),
+                Some(state) => egui::Color32::from_rgb(0, 80 + state.velocity.min(127), 0),
+            };
+            ui.painter().rect_filled(rect, 2.0, color);
+            if let Some(state) = state {
+                response.on_hover_text(format!(
+                    "Note {} (velocity {}), started {:.1}s ago{}",
+                    state.note,
+                    state.velocity,
+                    state.age.as_secs_f32(),
+                    if state.releasing { ", releasing" } else { "" }
+                ));
+            }
+        }
+    });
+}
+
 fn enum_combobox<T: strum::IntoEnumIterator + PartialEq + std::fmt::Debug>(
     ui: &mut egui::Ui,
     id: impl std::hash::Hash,
@@ -122,12 +203,49 @@ fn top_panel_contents(
                     // voice number:
                     nvoices = 1;
                 }
-                ui.add(egui::Slider::new(&mut nvoices, 1..=32).text("voices"));
+                ui.add(egui::Slider::new(&mut nvoices, 1..=MAX_VOICES).text("voices"));
+                ui.label("allocation:");
+                enum_combobox(
+                    ui,
+                    "voice-policy-combobox",
+                    &mut *arcs.dsp_voice_policy.write().unwrap(),
+                );
+                ui.label("on note off:");
+                enum_combobox(
+                    ui,
+                    "voice-release-combobox",
+                    &mut *arcs.dsp_voice_release.write().unwrap(),
+                );
+                ui.checkbox(&mut arcs.dsp_group_voices.write().unwrap(), "group voices")
+                    .on_hover_text("If unchecked, each voice also gets its own widgets");
             }
         }
     });
     *arcs.dsp_nvoices.write().unwrap() = nvoices;
 
+    // Setting whether the instrument is driven by an MPE controller:
+
+    if selected_dsp_type == DspType::Instrument {
+        let mut mpe = arcs.dsp_mpe.write().unwrap();
+        ui.horizontal(|ui| {
+            ui.label("MPE zone:");
+            enum_combobox(ui, "mpe-zone-combobox", &mut mpe.zone);
+            if mpe.zone != MpeZoneChoice::Off {
+                ui.add(egui::Slider::new(&mut mpe.member_channels, 1..=15).text("member channels"));
+                ui.add(
+                    egui::Slider::new(&mut mpe.pitch_bend_range, 1.0..=96.0)
+                        .text("pitch bend range (semitones)"),
+                );
+            }
+        });
+    }
+
+    tuning_contents(ui, arcs, async_executor, ed_state);
+    midi_transform_contents(ui, arcs);
+    cc_mappings_contents(ui, arcs);
+    param_slots_contents(ui, arcs);
+    routing_contents(ui, arcs);
+
     let mut selected_paths = arcs.selected_paths.write().unwrap();
 
     // Setting the Faust libraries path:
@@ -150,8 +268,15 @@ fn top_panel_contents(
         }
     }
 
+    compile_helper_contents(ui, arcs, ed_state);
+
     // Setting the DSP script and triggering a reload:
 
+    ui.add(
+        egui::Slider::new(&mut *arcs.swap_fade_ms.write().unwrap(), 0.0..=2000.0)
+            .text("reload crossfade (ms)"),
+    )
+    .on_hover_text("How long the old DSP takes to fade out when the script is reloaded");
     match &selected_paths.dsp_script {
         Some(path) => ui.label(format!("DSP script: {}", path.display())),
         None => ui.colored_label(egui::Color32::YELLOW, "No DSP script selected"),
@@ -175,4 +300,441 @@ fn top_panel_contents(
             }
         }
     }
+    let loaded = matches!(&*arcs.dsp_state.read().unwrap(), DspState::Loaded(_));
+    if ui
+        .add_enabled(loaded, egui::Button::new("Unload DSP"))
+        .on_hover_text("Stops the DSP. Reloading the script starts it again")
+        .clicked()
+    {
+        async_executor.execute_background(crate::Tasks::UnloadDsp);
+    }
+    load_status_contents(ui, &arcs.load_status.read().unwrap());
+}
+
+/// Shows whether a script is being compiled, and how long its last loading took
+fn load_status_contents(ui: &mut egui::Ui, status: &LoadStatus) {
+    let file_name = |script: &std::path::Path| {
+        script
+            .file_name()
+            .unwrap_or(script.as_os_str())
+            .to_string_lossy()
+            .into_owned()
+    };
+    match status {
+        LoadStatus::Idle => {}
+        LoadStatus::Queued { position, script } => {
+            ui.horizontal(|ui| {
+                ui.spinner();
+                ui.label(format!(
+                    "{}: waiting for {} other script(s) to compile",
+                    file_name(script),
+                    position
+                ));
+            });
+        }
+        LoadStatus::Compiling { started, script } => {
+            ui.horizontal(|ui| {
+                ui.spinner();
+                ui.label(format!(
+                    "Compiling {}... {:.1}s",
+                    file_name(script),
+                    started.elapsed().as_secs_f32()
+                ));
+            });
+        }
+        LoadStatus::Done {
+            script,
+            duration,
+            source,
+        } => {
+            let how = match source {
+                None => "failed",
+                Some(faust_jit::FactorySource::CacheHit) => "read from the cache",
+                Some(faust_jit::FactorySource::CacheMiss) => "compiled, not in the cache yet",
+                Some(faust_jit::FactorySource::Helper) => "compiled in a separate process",
+                Some(_) => "compiled",
+            };
+            ui.label(format!(
+                "{}: {} in {:.2}s",
+                file_name(script),
+                how,
+                duration.as_secs_f32()
+            ));
+        }
+    }
+}
+
+/// Setting whether scripts are compiled in a separate process
+fn compile_helper_contents(ui: &mut egui::Ui, arcs: &EditorArcs, ed_state: &mut EditorState) {
+    let mut settings = arcs.compile_helper.write().unwrap();
+    ui.horizontal(|ui| {
+        ui.checkbox(&mut settings.enabled, "Compile in a separate process")
+            .on_hover_text("So that a compiler crash doesn't take the host down");
+        if settings.enabled {
+            match (&settings.exe, settings.exe()) {
+                (Some(exe), _) => ui.label(format!("helper: {}", exe.display())),
+                (None, Some(exe)) => ui.label(format!("helper (found): {}", exe.display())),
+                (None, None) => ui.colored_label(egui::Color32::YELLOW, "No helper selected"),
+            };
+            if ui.button("Set helper (faust_jit_compile)").clicked() {
+                let mut dialog = egui_file::FileDialog::open_file(settings.exe());
+                dialog.open();
+                ed_state.helper_dialog = Some(dialog);
+            }
+            ui.add(
+                egui::Slider::new(&mut settings.timeout_secs, 1.0..=600.0)
+                    .logarithmic(true)
+                    .text("timeout (s)"),
+            );
+        }
+    });
+    if let Some(dialog) = &mut ed_state.helper_dialog {
+        if dialog.show(ui.ctx()).selected() {
+            if let Some(file) = dialog.path() {
+                settings.exe = Some(file.to_path_buf());
+            }
+        }
+    }
+}
+
+/// Selecting the Scala files describing the tuning of instruments
+fn tuning_contents(
+    ui: &mut egui::Ui,
+    arcs: &EditorArcs,
+    async_executor: &AsyncExecutor<crate::NihFaustJit>,
+    ed_state: &mut EditorState,
+) {
+    let mut tuning_paths = arcs.tuning_paths.write().unwrap();
+    let mut changed = false;
+    ui.horizontal(|ui| {
+        match &tuning_paths.scl {
+            Some(scl) => ui.label(format!("Tuning: {}", scl.display())),
+            None => ui.label("Tuning: 12-TET"),
+        };
+        if let Some(kbm) = &tuning_paths.kbm {
+            ui.label(format!("(mapping: {})", kbm.display()));
+        }
+        if ui.button("Set scale (.scl)").clicked() {
+            let mut dialog = egui_file::FileDialog::open_file(tuning_paths.scl.clone());
+            dialog.open();
+            ed_state.scl_dialog = Some(dialog);
+        }
+        if ui.button("Set keyboard mapping (.kbm)").clicked() {
+            let mut dialog = egui_file::FileDialog::open_file(tuning_paths.kbm.clone());
+            dialog.open();
+            ed_state.kbm_dialog = Some(dialog);
+        }
+        if ui.button("Reset to 12-TET").clicked() {
+            *tuning_paths = crate::TuningPaths::default();
+            changed = true;
+        }
+    });
+    if let Some(dialog) = &mut ed_state.scl_dialog {
+        if dialog.show(ui.ctx()).selected() {
+            if let Some(file) = dialog.path() {
+                tuning_paths.scl = Some(file.to_path_buf());
+                changed = true;
+            }
+        }
+    }
+    if let Some(dialog) = &mut ed_state.kbm_dialog {
+        if dialog.show(ui.ctx()).selected() {
+            if let Some(file) = dialog.path() {
+                tuning_paths.kbm = Some(file.to_path_buf());
+                changed = true;
+            }
+        }
+    }
+    if changed {
+        async_executor.execute_background(crate::Tasks::ReloadTuning);
+    }
+    if let Some(err) = &*arcs.tuning_error.read().unwrap() {
+        ui.colored_label(egui::Color32::LIGHT_RED, err);
+    }
+}
+
+/// Setting how incoming MIDI is transformed before reaching the DSP. The DSP
+/// gets the new transform as soon as it changes
+fn midi_transform_contents(ui: &mut egui::Ui, arcs: &EditorArcs) {
+    let mut settings = arcs.midi_transform.write().unwrap();
+    let last_settings = settings.clone();
+    ui.collapsing("MIDI input", |ui| {
+        ui.horizontal(|ui| {
+            ui.label("channels:");
+            for chan in 0..16 {
+                let mut selected = settings.channels & (1 << chan) != 0;
+                if ui
+                    .toggle_value(&mut selected, format!("{}", chan + 1))
+                    .changed()
+                {
+                    settings.channels ^= 1 << chan;
+                }
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label("notes:");
+            ui.add(egui::DragValue::new(&mut settings.lowest_note).clamp_range(0..=127));
+            ui.label("to");
+            ui.add(egui::DragValue::new(&mut settings.highest_note).clamp_range(0..=127));
+            ui.add(egui::Slider::new(&mut settings.transpose, -48..=48).text("transpose"));
+        });
+        ui.horizontal(|ui| {
+            ui.label("velocity:");
+            enum_combobox(ui, "velocity-curve-combobox", &mut settings.velocity_curve);
+            match settings.velocity_curve {
+                VelocityCurveChoice::Linear => {}
+                VelocityCurveChoice::Power => {
+                    ui.add(
+                        egui::Slider::new(&mut settings.velocity_exponent, 0.1..=4.0)
+                            .text("exponent"),
+                    );
+                }
+                VelocityCurveChoice::Fixed => {
+                    ui.add(egui::Slider::new(&mut settings.fixed_velocity, 1..=127));
+                }
+            }
+        });
+        let mut removed = None;
+        for (i, (from, to)) in settings.cc_remaps.iter_mut().enumerate() {
+            ui.horizontal(|ui| {
+                ui.label("CC");
+                ui.add(egui::DragValue::new(from).clamp_range(0..=127));
+                ui.label("is received as CC");
+                ui.add(egui::DragValue::new(to).clamp_range(0..=127));
+                if ui.button("Remove").clicked() {
+                    removed = Some(i);
+                }
+            });
+        }
+        if let Some(i) = removed {
+            settings.cc_remaps.remove(i);
+        }
+        if ui.button("Remap a CC").clicked() {
+            settings.cc_remaps.push((1, 1));
+        }
+    });
+    if *settings != last_settings {
+        if let DspState::Loaded(dsp) = &*arcs.dsp_state.read().unwrap() {
+            dsp.set_midi_transform(settings.to_transform());
+        }
+    }
+}
+
+/// Creating the mapping of a controller once it is learned, and telling which
+/// widget is waiting for one
+fn midi_learn_contents(
+    ui: &mut egui::Ui,
+    arcs: &EditorArcs,
+    dsp: &faust_jit::SingletonDsp,
+    learn: &mut faust_jit_egui::MidiLearn,
+) {
+    if let Some(mapping) = learn.poll(dsp) {
+        let mut mappings = arcs.cc_mappings.write().unwrap();
+        mappings.push(crate::CcMappingSettings::from_mapping(mapping));
+        crate::set_cc_mappings(dsp, &mappings);
+    }
+    if let Some(target) = learn.target().map(str::to_owned) {
+        ui.horizontal(|ui| {
+            ui.colored_label(
+                egui::Color32::YELLOW,
+                format!("Move a MIDI controller to map it to {}", target),
+            );
+            if ui.button("Cancel").clicked() {
+                learn.cancel();
+            }
+        });
+    }
+}
+
+/// Editing the controllers mapped to widgets by MIDI learn
+fn cc_mappings_contents(ui: &mut egui::Ui, arcs: &EditorArcs) {
+    let mut mappings = arcs.cc_mappings.write().unwrap();
+    let last_mappings = mappings.clone();
+    ui.collapsing(format!("MIDI mappings ({})", mappings.len()), |ui| {
+        if mappings.is_empty() {
+            ui.label("Right-click a widget to MIDI learn it");
+            return;
+        }
+        let mut removed = None;
+        egui::Grid::new("cc-mappings-grid")
+            .striped(true)
+            .show(ui, |ui| {
+                for header in ["channel", "CC", "widget", "min", "max", "curve", ""] {
+                    ui.label(header);
+                }
+                ui.end_row();
+                for (i, mapping) in mappings.iter_mut().enumerate() {
+                    let mut channel = mapping.channel.map_or(0, |c| c + 1);
+                    ui.add(
+                        egui::DragValue::new(&mut channel)
+                            .clamp_range(0..=16)
+                            .custom_formatter(|n, _| match n as u8 {
+                                0 => "any".to_owned(),
+                                n => n.to_string(),
+                            }),
+                    );
+                    mapping.channel = channel.checked_sub(1);
+                    ui.add(egui::DragValue::new(&mut mapping.cc).clamp_range(0..=127));
+                    ui.label(&mapping.path);
+                    ui.add(egui::DragValue::new(&mut mapping.min).speed(0.01));
+                    ui.add(egui::DragValue::new(&mut mapping.max).speed(0.01));
+                    ui.add(egui::Slider::new(&mut mapping.exponent, 0.1..=4.0).logarithmic(true))
+                        .on_hover_text("1 is linear");
+                    if ui.button("Remove").clicked() {
+                        removed = Some(i);
+                    }
+                    ui.end_row();
+                }
+            });
+        if let Some(i) = removed {
+            mappings.remove(i);
+        }
+    });
+    if *mappings != last_mappings {
+        if let DspState::Loaded(dsp) = &*arcs.dsp_state.read().unwrap() {
+            crate::set_cc_mappings(dsp, &mappings);
+        }
+    }
+}
+
+/// Choosing which widget each host parameter slot drives
+fn param_slots_contents(ui: &mut egui::Ui, arcs: &EditorArcs) {
+    let DspState::Loaded(dsp) = &*arcs.dsp_state.read().unwrap() else {
+        return;
+    };
+    let mut assignment = arcs.slot_assignment.write().unwrap();
+    ui.collapsing("Host parameters", |ui| {
+        let auto_resp = ui
+            .checkbox(&mut assignment.auto, "assign automatically")
+            .on_hover_text("Assign the slots to the first widgets each time a DSP is loaded");
+        if auto_resp.changed() && assignment.auto {
+            arcs.slots_state.reassign(dsp, &mut assignment);
+        }
+        let targets = crate::param_slots::slot_targets(dsp);
+        egui::ScrollArea::vertical()
+            .max_height(200.0)
+            .show(ui, |ui| {
+                egui::Grid::new("param-slots-grid")
+                    .striped(true)
+                    .show(ui, |ui| {
+                        for slot in 0..crate::NUM_PARAM_SLOTS {
+                            ui.label(format!("Slot {}", slot + 1));
+                            let current = assignment.paths[slot].clone();
+                            let mut selected = current.clone();
+                            egui::ComboBox::from_id_source(("param-slot-combobox", slot))
+                                .selected_text(current.as_deref().unwrap_or("(none)"))
+                                .width(300.0)
+                                .show_ui(ui, |ui| {
+                                    ui.selectable_value(&mut selected, None, "(none)");
+                                    for target in &targets {
+                                        ui.selectable_value(
+                                            &mut selected,
+                                            Some(target.path.clone()),
+                                            &target.path,
+                                        );
+                                    }
+                                });
+                            if current.is_some() && arcs.slots_state.target(slot).is_none() {
+                                ui.colored_label(egui::Color32::YELLOW, "not in this DSP");
+                            }
+                            if selected != current {
+                                arcs.slots_state
+                                    .assign(dsp, &mut assignment, slot, selected);
+                            }
+                            ui.end_row();
+                        }
+                    });
+            });
+    });
+}
+
+/// Editing the gains from the host channels to the DSP channels and back
+fn routing_contents(ui: &mut egui::Ui, arcs: &EditorArcs) {
+    let DspState::Loaded(dsp) = &*arcs.dsp_state.read().unwrap() else {
+        return;
+    };
+    let host_layout = *arcs.host_layout.read().unwrap();
+    let counts = crate::routing::ChannelCounts::new(&host_layout, &dsp.info);
+    let last_routing = arcs.routing.read().unwrap().clone();
+    let mut routing = last_routing.clone();
+    ui.collapsing("Channel routing", |ui| {
+        if !crate::io_layout_fits(&dsp.info, &host_layout) {
+            if let Some(best) = crate::best_io_layout(&dsp.info) {
+                ui.colored_label(
+                    egui::Color32::YELLOW,
+                    format!(
+                        "Select the {} layout in the host to give the DSP all its channels",
+                        best.names.layout.unwrap_or("?")
+                    ),
+                );
+            }
+        }
+        ui.horizontal(|ui| {
+            if ui
+                .checkbox(&mut routing.auto, "automatic")
+                .on_hover_text("Reset the routing each time a DSP is loaded")
+                .changed()
+                && routing.auto
+            {
+                routing = crate::routing::RoutingMatrix::default_for(counts);
+            }
+        });
+        let host_input_name = |h: usize| {
+            if h < counts.host_main {
+                format!("In {}", h + 1)
+            } else {
+                format!("SC {}", h - counts.host_main + 1)
+            }
+        };
+        let mut edited = false;
+        let mut gain_grid = |ui: &mut egui::Ui,
+                             id: &str,
+                             rows: &mut [Vec<f32>],
+                             row_name: &dyn Fn(usize) -> String,
+                             col_name: &dyn Fn(usize) -> String| {
+            egui::Grid::new(id).striped(true).show(ui, |ui| {
+                ui.label("");
+                for c in 0..rows.first().map_or(0, Vec::len) {
+                    ui.label(col_name(c));
+                }
+                ui.end_row();
+                for (r, row) in rows.iter_mut().enumerate() {
+                    ui.label(row_name(r));
+                    for gain in row {
+                        edited |= ui
+                            .add(
+                                egui::DragValue::new(gain)
+                                    .clamp_range(-2.0..=2.0)
+                                    .speed(0.01)
+                                    .fixed_decimals(2),
+                            )
+                            .changed();
+                    }
+                    ui.end_row();
+                }
+            });
+        };
+        ui.label("Host inputs to DSP inputs:");
+        gain_grid(
+            ui,
+            "routing-inputs-grid",
+            &mut routing.inputs,
+            &|i| format!("DSP in {}", i + 1),
+            &host_input_name,
+        );
+        ui.label("DSP outputs to host outputs:");
+        gain_grid(
+            ui,
+            "routing-outputs-grid",
+            &mut routing.outputs,
+            &|o| format!("Out {}", o + 1),
+            &|d| format!("DSP out {}", d + 1),
+        );
+        if edited {
+            routing.auto = false;
+        }
+    });
+    if routing != last_routing {
+        *arcs.routing.write().unwrap() = routing;
+    }
 }
@@ -7,27 +7,181 @@ use serde::{Deserialize, Serialize};
 use std::{
     path::PathBuf,
     sync::{atomic::Ordering, Arc, RwLock},
+    time::{Duration, Instant},
 };
 
+mod dry_wet;
 mod editor;
+mod param_slots;
+mod routing;
+mod swap_fade;
+
+use param_slots::{SlotAssignment, SlotParams, SlotsState, NUM_PARAM_SLOTS};
+use swap_fade::{FadingDsp, SwapFader};
 
 #[derive(Debug)]
 enum DspState {
     NoDspScript,
+    /// The selected script was stopped by [`Tasks::UnloadDsp`]. Outputs
+    /// silence
+    Unloaded,
     Loaded(faust_jit::SingletonDsp),
     Failed(String),
 }
 
+/// Where the loading of the selected script is at. Shown in the GUI alongside
+/// the current [`DspState`], which is only replaced once the loading is done
+#[derive(Debug, Default)]
+enum LoadStatus {
+    #[default]
+    Idle,
+    /// Waiting for `position` other scripts (possibly of other instances of
+    /// the plugin) to be compiled first
+    Queued {
+        position: usize,
+        script: PathBuf,
+    },
+    Compiling {
+        started: Instant,
+        script: PathBuf,
+    },
+    /// `source` is `None` if the script failed to load
+    Done {
+        script: PathBuf,
+        duration: Duration,
+        source: Option<faust_jit::FactorySource>,
+    },
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SelectedPaths {
     dsp_script: Option<std::path::PathBuf>,
     dsp_lib_path: std::path::PathBuf,
 }
 
+/// The Scala files describing the tuning of instruments. No .scl file means
+/// standard 12-TET tuning
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TuningPaths {
+    scl: Option<std::path::PathBuf>,
+    kbm: Option<std::path::PathBuf>,
+}
+
+impl TuningPaths {
+    /// Reads the tuning files and gives the tuning to the DSP
+    fn apply_to(&self, dsp: &faust_jit::SingletonDsp) -> Result<(), String> {
+        let tuning = match &self.scl {
+            Some(scl) => Some(faust_jit::Tuning::from_scala_files(
+                scl,
+                self.kbm.as_deref(),
+            )?),
+            None => None,
+        };
+        dsp.set_tuning(tuning.as_ref());
+        Ok(())
+    }
+}
+
+/// Whether scripts are compiled in a separate process (the `faust_jit_compile`
+/// binary), so that a compiler crash doesn't take the host down
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CompileHelperSettings {
+    enabled: bool,
+    exe: Option<std::path::PathBuf>,
+    /// After which the compilation is stopped
+    timeout_secs: f32,
+}
+
+impl Default for CompileHelperSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            exe: None,
+            timeout_secs: 60.0,
+        }
+    }
+}
+
+impl CompileHelperSettings {
+    /// The selected helper, or else the one next to the plugin binary (where
+    /// the flake installs it), if it exists
+    pub(crate) fn exe(&self) -> Option<PathBuf> {
+        self.exe.clone().or_else(|| {
+            let name = format!("faust_jit_compile{}", std::env::consts::EXE_SUFFIX);
+            let exe = plugin_binary()?.parent()?.join(name);
+            exe.exists().then_some(exe)
+        })
+    }
+
+    fn to_helper(&self) -> Result<Option<faust_jit::CompileHelper>, String> {
+        if !self.enabled {
+            return Ok(None);
+        }
+        let exe = self
+            .exe()
+            .ok_or("No compile helper (faust_jit_compile) selected or found next to the plugin")?;
+        Ok(Some(faust_jit::CompileHelper {
+            exe,
+            timeout: Duration::from_secs_f32(self.timeout_secs),
+        }))
+    }
+}
+
+/// The path of the binary this code was loaded from: the plugin library when
+/// loaded by a host, the executable when running standalone
+#[cfg(unix)]
+fn plugin_binary() -> Option<PathBuf> {
+    use std::os::unix::ffi::OsStrExt;
+    let mut info: libc::Dl_info = unsafe { std::mem::zeroed() };
+    let found = unsafe { libc::dladdr(plugin_binary as *const libc::c_void, &mut info) };
+    if found == 0 || info.dli_fname.is_null() {
+        return None;
+    }
+    let path = unsafe { std::ffi::CStr::from_ptr(info.dli_fname) };
+    Some(PathBuf::from(std::ffi::OsStr::from_bytes(path.to_bytes())))
+}
+
+#[cfg(not(unix))]
+fn plugin_binary() -> Option<PathBuf> {
+    std::env::current_exe().ok()
+}
+
+/// The maximum number of voices of an instrument that can be chosen in the GUI
+/// (and reported to the host)
+pub const MAX_VOICES: i32 = 32;
+
 pub struct NihFaustJit {
     sample_rate: Arc<AtomicF32>,
+    /// The audio IO layout chosen by the host
+    host_layout: Arc<RwLock<AudioIOLayout>>,
+    /// Only used by the audio thread
+    router: routing::Router,
+    /// Only used by the audio thread
+    dry_wet: dry_wet::DryWet,
+    /// The latency last reported to the host, in samples
+    reported_latency: u32,
     params: Arc<NihFaustJitParams>,
     dsp_state: Arc<RwLock<DspState>>,
+    /// Why the last reload failed, if it failed while a DSP was already loaded
+    /// (which then keeps running)
+    reload_error: Arc<RwLock<Option<String>>>,
+    load_status: Arc<RwLock<LoadStatus>>,
+    /// So that a reload supersedes the previous one if it didn't start yet
+    compile_requester: faust_jit::CompileRequester,
+    /// The DSP that was last replaced, while it fades out
+    fading_dsp: Arc<RwLock<Option<FadingDsp>>>,
+    /// Only used by the audio thread
+    swap_fader: SwapFader,
+    /// Why the selected tuning files couldn't be read, if they couldn't
+    tuning_error: Arc<RwLock<Option<String>>>,
+    /// Whether the transport was playing during the last processed buffer
+    was_playing: bool,
+    /// The values of the parameter slots during the last processed buffer
+    last_slot_values: [f32; NUM_PARAM_SLOTS],
+    /// Only used by the audio thread
+    poly_modulations: param_slots::PolyModulations,
+    /// The voice capacity last reported to the host
+    reported_voice_capacity: u32,
 }
 
 #[derive(Params)]
@@ -35,6 +189,25 @@ struct NihFaustJitParams {
     #[id = "gain"]
     pub gain: FloatParam,
 
+    #[id = "mix"]
+    pub mix: FloatParam,
+
+    #[id = "bypass"]
+    pub bypass: BoolParam,
+
+    /// Host parameters that can be assigned to widgets of the DSP
+    #[nested(array)]
+    slots: [SlotParams; NUM_PARAM_SLOTS],
+
+    /// Not persisted as such, only its `touched` field is
+    slots_state: Arc<SlotsState>,
+
+    #[persist = "param-slots"]
+    slot_assignment: Arc<RwLock<SlotAssignment>>,
+
+    #[persist = "param-slots-touched"]
+    slots_touched: Arc<std::sync::atomic::AtomicU64>,
+
     #[persist = "editor-state"]
     nih_egui_state: Arc<nih_plug_egui::EguiState>,
 
@@ -43,6 +216,37 @@ struct NihFaustJitParams {
 
     #[persist = "dsp-nvoices"]
     dsp_nvoices: Arc<RwLock<i32>>,
+
+    #[persist = "dsp-voice-policy"]
+    dsp_voice_policy: Arc<RwLock<VoicePolicyChoice>>,
+
+    #[persist = "dsp-voice-release"]
+    dsp_voice_release: Arc<RwLock<VoiceReleaseChoice>>,
+
+    #[persist = "dsp-group-voices"]
+    dsp_group_voices: Arc<RwLock<bool>>,
+
+    #[persist = "dsp-mpe"]
+    dsp_mpe: Arc<RwLock<MpeSettings>>,
+
+    #[persist = "tuning-paths"]
+    tuning_paths: Arc<RwLock<TuningPaths>>,
+
+    #[persist = "midi-transform"]
+    midi_transform: Arc<RwLock<MidiTransformSettings>>,
+
+    #[persist = "cc-mappings"]
+    cc_mappings: Arc<RwLock<Vec<CcMappingSettings>>>,
+
+    #[persist = "routing"]
+    routing: Arc<RwLock<routing::RoutingMatrix>>,
+
+    /// How long the crossfade from a DSP to the one replacing it lasts
+    #[persist = "swap-fade-ms"]
+    swap_fade_ms: Arc<RwLock<f32>>,
+
+    #[persist = "compile-helper"]
+    compile_helper: Arc<RwLock<CompileHelperSettings>>,
 }
 
 impl NihFaustJit {
@@ -52,7 +256,23 @@ impl NihFaustJit {
             nih_egui_state: Arc::clone(&self.params.nih_egui_state),
             selected_paths: Arc::clone(&self.params.selected_paths),
             dsp_state: Arc::clone(&self.dsp_state),
+            reload_error: Arc::clone(&self.reload_error),
+            load_status: Arc::clone(&self.load_status),
             dsp_nvoices: Arc::clone(&self.params.dsp_nvoices),
+            dsp_voice_policy: Arc::clone(&self.params.dsp_voice_policy),
+            dsp_voice_release: Arc::clone(&self.params.dsp_voice_release),
+            dsp_group_voices: Arc::clone(&self.params.dsp_group_voices),
+            dsp_mpe: Arc::clone(&self.params.dsp_mpe),
+            tuning_paths: Arc::clone(&self.params.tuning_paths),
+            tuning_error: Arc::clone(&self.tuning_error),
+            midi_transform: Arc::clone(&self.params.midi_transform),
+            cc_mappings: Arc::clone(&self.params.cc_mappings),
+            slots_state: Arc::clone(&self.params.slots_state),
+            slot_assignment: Arc::clone(&self.params.slot_assignment),
+            host_layout: Arc::clone(&self.host_layout),
+            routing: Arc::clone(&self.params.routing),
+            swap_fade_ms: Arc::clone(&self.params.swap_fade_ms),
+            compile_helper: Arc::clone(&self.params.compile_helper),
         }
     }
 }
@@ -61,18 +281,46 @@ impl Default for NihFaustJit {
     fn default() -> Self {
         Self {
             sample_rate: Arc::new(AtomicF32::new(0.0)),
+            host_layout: Arc::new(RwLock::new(Self::AUDIO_IO_LAYOUTS[0])),
+            router: routing::Router::default(),
+            dry_wet: dry_wet::DryWet::default(),
+            reported_latency: 0,
             params: Arc::new(NihFaustJitParams::default()),
             dsp_state: Arc::new(RwLock::new(DspState::NoDspScript)),
+            reload_error: Arc::new(RwLock::new(None)),
+            load_status: Arc::new(RwLock::new(LoadStatus::default())),
+            compile_requester: faust_jit::CompileRequester::new(),
+            fading_dsp: Arc::new(RwLock::new(None)),
+            swap_fader: SwapFader::default(),
+            tuning_error: Arc::new(RwLock::new(None)),
+            was_playing: false,
+            last_slot_values: [f32::NAN; NUM_PARAM_SLOTS],
+            poly_modulations: param_slots::PolyModulations::default(),
+            reported_voice_capacity: MAX_VOICES as u32,
         }
     }
 }
 
 impl Default for NihFaustJitParams {
     fn default() -> Self {
+        let slots_state = Arc::new(SlotsState::default());
         Self {
             gain: FloatParam::new("Gain", 1.0, FloatRange::Linear { min: 0.0, max: 1.0 })
                 .with_smoother(SmoothingStyle::Linear(50.0)),
 
+            mix: FloatParam::new("Mix", 1.0, FloatRange::Linear { min: 0.0, max: 1.0 })
+                .with_smoother(SmoothingStyle::Linear(50.0))
+                .with_unit("%")
+                .with_value_to_string(formatters::v2s_f32_percentage(0))
+                .with_string_to_value(formatters::s2v_f32_percentage()),
+
+            bypass: BoolParam::new("Bypass", false).make_bypass(),
+
+            slots: param_slots::new_slots(&slots_state),
+            slot_assignment: Arc::new(RwLock::new(SlotAssignment::default())),
+            slots_touched: Arc::clone(&slots_state.touched),
+            slots_state,
+
             nih_egui_state: nih_plug_egui::EguiState::from_size(800, 700),
 
             selected_paths: Arc::new(RwLock::new(SelectedPaths {
@@ -81,12 +329,68 @@ impl Default for NihFaustJitParams {
             })),
 
             dsp_nvoices: Arc::new(RwLock::new(-1)),
+
+            dsp_voice_policy: Arc::new(RwLock::new(VoicePolicyChoice::StealOldest)),
+
+            dsp_voice_release: Arc::new(RwLock::new(VoiceReleaseChoice::Release)),
+
+            dsp_group_voices: Arc::new(RwLock::new(false)),
+
+            dsp_mpe: Arc::new(RwLock::new(MpeSettings::default())),
+
+            tuning_paths: Arc::new(RwLock::new(TuningPaths::default())),
+
+            midi_transform: Arc::new(RwLock::new(MidiTransformSettings::default())),
+
+            cc_mappings: Arc::new(RwLock::new(vec![])),
+
+            routing: Arc::new(RwLock::new(routing::RoutingMatrix::default())),
+
+            swap_fade_ms: Arc::new(RwLock::new(50.0)),
+
+            compile_helper: Arc::new(RwLock::new(CompileHelperSettings::default())),
         }
     }
 }
 
 pub enum Tasks {
     ReloadDsp,
+    ReloadTuning,
+    /// Stops the current DSP, without unselecting its script
+    UnloadDsp,
+    /// Drops the DSP that was replaced, once it has faded out
+    ReleaseFadedDsp,
+}
+
+/// A SysEx message, forwarded as is to the DSP
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawSysEx {
+    buffer: [u8; RawSysEx::MAX_LEN],
+    len: usize,
+}
+
+impl RawSysEx {
+    const MAX_LEN: usize = 512;
+}
+
+impl SysExMessage for RawSysEx {
+    type Buffer = [u8; RawSysEx::MAX_LEN];
+
+    fn from_buffer(buffer: &[u8]) -> Option<Self> {
+        if buffer.len() > Self::MAX_LEN {
+            return None;
+        }
+        let mut sysex = Self {
+            buffer: [0; Self::MAX_LEN],
+            len: buffer.len(),
+        };
+        sysex.buffer[..buffer.len()].copy_from_slice(buffer);
+        Some(sysex)
+    }
+
+    fn to_buffer(self) -> (Self::Buffer, usize) {
+        (self.buffer, self.len)
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy, strum_macros::EnumIter)]
@@ -102,11 +406,283 @@ impl DspType {
         match faust_jit::DspLoadMode::from_nvoices(nvoices) {
             faust_jit::DspLoadMode::AutoDetect => DspType::AutoDetect,
             faust_jit::DspLoadMode::Effect => DspType::Effect,
-            faust_jit::DspLoadMode::Instrument { nvoices: _ } => DspType::Instrument,
+            faust_jit::DspLoadMode::Instrument { .. } => DspType::Instrument,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize, strum_macros::EnumIter)]
+// We don't reuse faust_jit::VoicePolicy because we need it to be serializable
+pub enum VoicePolicyChoice {
+    StealOldest,
+    StealQuietest,
+    StealLowest,
+    StealHighest,
+    MonoLegato,
+    MonoRetrigger,
+}
+
+impl VoicePolicyChoice {
+    fn to_policy(self) -> faust_jit::VoicePolicy {
+        match self {
+            Self::StealOldest => faust_jit::VoicePolicy::StealOldest,
+            Self::StealQuietest => faust_jit::VoicePolicy::StealQuietest,
+            Self::StealLowest => faust_jit::VoicePolicy::StealLowest,
+            Self::StealHighest => faust_jit::VoicePolicy::StealHighest,
+            Self::MonoLegato => faust_jit::VoicePolicy::MonoLegato,
+            Self::MonoRetrigger => faust_jit::VoicePolicy::MonoRetrigger,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize, strum_macros::EnumIter)]
+// We don't reuse faust_jit::VoiceRelease because we need it to be serializable
+pub enum VoiceReleaseChoice {
+    Release,
+    Cut,
+}
+
+impl VoiceReleaseChoice {
+    fn to_release(self) -> faust_jit::VoiceRelease {
+        match self {
+            Self::Release => faust_jit::VoiceRelease::Release,
+            Self::Cut => faust_jit::VoiceRelease::Cut,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize, strum_macros::EnumIter)]
+pub enum MpeZoneChoice {
+    Off,
+    Lower,
+    Upper,
+}
+
+/// How MPE should be used by instruments. We don't reuse faust_jit::MpeConfig
+/// because it doesn't implement Serialize
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct MpeSettings {
+    zone: MpeZoneChoice,
+    member_channels: u8,
+    pitch_bend_range: f32,
+}
+
+impl Default for MpeSettings {
+    fn default() -> Self {
+        let config = faust_jit::MpeConfig::default();
+        Self {
+            zone: MpeZoneChoice::Off,
+            member_channels: config.member_channels,
+            pitch_bend_range: config.pitch_bend_range,
         }
     }
 }
 
+impl MpeSettings {
+    fn to_config(self) -> Option<faust_jit::MpeConfig> {
+        let zone = match self.zone {
+            MpeZoneChoice::Off => return None,
+            MpeZoneChoice::Lower => faust_jit::MpeZone::Lower,
+            MpeZoneChoice::Upper => faust_jit::MpeZone::Upper,
+        };
+        Some(faust_jit::MpeConfig {
+            zone,
+            member_channels: self.member_channels,
+            pitch_bend_range: self.pitch_bend_range,
+            ..Default::default()
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize, strum_macros::EnumIter)]
+pub enum VelocityCurveChoice {
+    Linear,
+    Power,
+    Fixed,
+}
+
+/// How incoming MIDI is transformed before reaching the DSP. We don't reuse
+/// faust_jit::MidiTransform because it doesn't implement Serialize
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MidiTransformSettings {
+    channels: u16,
+    lowest_note: u8,
+    highest_note: u8,
+    transpose: i8,
+    velocity_curve: VelocityCurveChoice,
+    velocity_exponent: f32,
+    fixed_velocity: u8,
+    cc_remaps: Vec<(u8, u8)>,
+}
+
+impl Default for MidiTransformSettings {
+    fn default() -> Self {
+        Self {
+            channels: 0xFFFF,
+            lowest_note: 0,
+            highest_note: 127,
+            transpose: 0,
+            velocity_curve: VelocityCurveChoice::Linear,
+            velocity_exponent: 1.0,
+            fixed_velocity: 100,
+            cc_remaps: vec![],
+        }
+    }
+}
+
+impl MidiTransformSettings {
+    fn to_transform(&self) -> faust_jit::MidiTransform {
+        faust_jit::MidiTransform {
+            channels: self.channels,
+            note_range: self.lowest_note..=self.highest_note,
+            transpose: self.transpose,
+            velocity_curve: match self.velocity_curve {
+                VelocityCurveChoice::Linear => faust_jit::VelocityCurve::Linear,
+                VelocityCurveChoice::Power => {
+                    faust_jit::VelocityCurve::Power(self.velocity_exponent)
+                }
+                VelocityCurveChoice::Fixed => faust_jit::VelocityCurve::Fixed(self.fixed_velocity),
+            },
+            cc_remaps: self.cc_remaps.clone(),
+        }
+    }
+}
+
+/// A MIDI controller driving a widget of the DSP. We don't reuse
+/// faust_jit::CcMapping because it doesn't implement Serialize
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CcMappingSettings {
+    channel: Option<u8>,
+    cc: u8,
+    path: String,
+    min: f32,
+    max: f32,
+    exponent: f32,
+}
+
+impl CcMappingSettings {
+    fn from_mapping(mapping: faust_jit::CcMapping) -> Self {
+        Self {
+            channel: mapping.channel,
+            cc: mapping.cc,
+            path: mapping.path,
+            min: mapping.min,
+            max: mapping.max,
+            exponent: mapping.exponent,
+        }
+    }
+
+    fn to_mapping(&self) -> faust_jit::CcMapping {
+        faust_jit::CcMapping {
+            channel: self.channel,
+            cc: self.cc,
+            path: self.path.clone(),
+            min: self.min,
+            max: self.max,
+            exponent: self.exponent,
+        }
+    }
+}
+
+const MONO: &[NonZeroU32] = &[new_nonzero_u32(1)];
+const STEREO: &[NonZeroU32] = &[new_nonzero_u32(2)];
+const QUAD: &[NonZeroU32] = &[new_nonzero_u32(4)];
+const SURROUND_5_1: &[NonZeroU32] = &[new_nonzero_u32(6)];
+const SURROUND_7_1: &[NonZeroU32] = &[new_nonzero_u32(8)];
+
+/// An audio IO layout with as many input as output channels, and a sidechain
+/// input port as wide as the main ports. `width` is the (only) channel count of
+/// the sidechain port
+const fn io_layout(width: &'static [NonZeroU32], name: &'static str) -> AudioIOLayout {
+    AudioIOLayout {
+        main_input_channels: Some(width[0]),
+        main_output_channels: Some(width[0]),
+        aux_input_ports: width,
+        aux_output_ports: &[],
+        names: PortNames {
+            layout: Some(name),
+            aux_inputs: &["Sidechain"],
+            ..PortNames::const_default()
+        },
+    }
+}
+
+/// Whether a layout gives a DSP all the channels it needs, so that the default
+/// routing doesn't need to downmix or drop any of them
+fn io_layout_fits(info: &faust_jit::DspInfo, layout: &AudioIOLayout) -> bool {
+    let counts = routing::ChannelCounts::new(layout, info);
+    counts.dsp_outputs <= counts.host_main
+        && counts.dsp_inputs <= counts.host_main + counts.host_sidechain
+}
+
+/// The smallest of our layouts that fits a DSP (see [`io_layout_fits`])
+fn best_io_layout(info: &faust_jit::DspInfo) -> Option<&'static AudioIOLayout> {
+    NihFaustJit::AUDIO_IO_LAYOUTS
+        .iter()
+        .filter(|layout| io_layout_fits(info, layout))
+        .min_by_key(|layout| layout.main_output_channels)
+}
+
+/// Checks that the plugin can route the channels of a DSP
+fn check_dsp_channels(info: &faust_jit::DspInfo) -> Result<(), String> {
+    let max = routing::MAX_DSP_CHANNELS as i32;
+    if info.num_inputs <= max && info.num_outputs <= max {
+        Ok(())
+    } else {
+        Err(format!(
+            "DSP has {} input and {} output channels. Max is {} for each",
+            info.num_inputs, info.num_outputs, max
+        ))
+    }
+}
+
+/// Gives to the DSP the controllers that are mapped to its widgets
+fn set_cc_mappings(dsp: &faust_jit::SingletonDsp, mappings: &[CcMappingSettings]) {
+    let mappings: Vec<_> = mappings.iter().map(CcMappingSettings::to_mapping).collect();
+    dsp.set_cc_mappings(&mappings);
+}
+
+/// Replaces the DSP state, and lets the audio thread fade out the old DSP (if
+/// any) during `fade_length` samples, with the routing it was used with. The
+/// routing is then fitted to `new_counts` (the channels of the new DSP, if
+/// any), under the same locks, so that the audio thread never routes a DSP
+/// through the matrix of another. This is the only place where the whole DSP
+/// state is locked in write mode
+fn swap_dsp_state(
+    dsp_state: &RwLock<DspState>,
+    fading_dsp: &RwLock<Option<FadingDsp>>,
+    routing: &RwLock<routing::RoutingMatrix>,
+    new_dsp_state: DspState,
+    new_counts: Option<routing::ChannelCounts>,
+    fade_length: usize,
+) {
+    // The locks are taken in the same order as in the audio thread:
+    let mut fading_dsp = fading_dsp.write().unwrap();
+    let mut dsp_state = dsp_state.write().unwrap();
+    let mut routing = routing.write().unwrap();
+    let old_routing = routing.clone();
+    if let Some(counts) = new_counts {
+        routing.fit(counts);
+    }
+    drop(routing);
+    let old_dsp_state = std::mem::replace(&mut *dsp_state, new_dsp_state);
+    drop(dsp_state);
+    let (new_fading, dropped_state) = match old_dsp_state {
+        DspState::Loaded(old_dsp) if fade_length > 0 => {
+            old_dsp.all_notes_off(false);
+            (
+                Some(FadingDsp::new(old_dsp, old_routing, fade_length)),
+                None,
+            )
+        }
+        old_dsp_state => (None, Some(old_dsp_state)),
+    };
+    let dropped_fading = std::mem::replace(&mut *fading_dsp, new_fading);
+    drop(fading_dsp);
+    // Deallocating the DSPs only once the audio thread can run again:
+    drop((dropped_fading, dropped_state));
+}
+
 impl Plugin for NihFaustJit {
     const NAME: &'static str = "nih-faust-jit";
     const VENDOR: &'static str = "Yves Pares";
@@ -117,30 +693,27 @@ impl Plugin for NihFaustJit {
 
     // The first audio IO layout is used as the default. The other layouts may be selected either
     // explicitly or automatically by the host or the user depending on the plugin API/backend.
-    const AUDIO_IO_LAYOUTS: &'static [AudioIOLayout] = &[AudioIOLayout {
-        main_input_channels: NonZeroU32::new(2),
-        main_output_channels: NonZeroU32::new(2),
-
-        aux_input_ports: &[],
-        aux_output_ports: &[],
-
-        // Individual ports and the layout as a whole can be named here. By default these names
-        // are generated as needed. This layout will be called 'Stereo', while a layout with
-        // only one input and output channel would be called 'Mono'.
-        names: PortNames::const_default(),
-    }];
+    const AUDIO_IO_LAYOUTS: &'static [AudioIOLayout] = &[
+        io_layout(STEREO, "Stereo"),
+        io_layout(MONO, "Mono"),
+        io_layout(QUAD, "Quad"),
+        io_layout(SURROUND_5_1, "5.1"),
+        io_layout(SURROUND_7_1, "7.1"),
+    ];
 
     const MIDI_INPUT: MidiConfig = MidiConfig::MidiCCs;
-    const MIDI_OUTPUT: MidiConfig = MidiConfig::None;
+    const MIDI_OUTPUT: MidiConfig = MidiConfig::MidiCCs;
 
     const SAMPLE_ACCURATE_AUTOMATION: bool = true;
 
-    type SysExMessage = ();
+    type SysExMessage = RawSysEx;
 
     type BackgroundTask = Tasks;
 
     fn task_executor(&mut self) -> TaskExecutor<Self> {
         let sample_rate_arc = Arc::clone(&self.sample_rate);
+        let host_layout_arc = Arc::clone(&self.host_layout);
+        let routing_arc = Arc::clone(&self.params.routing);
         // This function may be called before self.sample_rate has been properly
         // initialized, and the task executor closure cannot borrow self. This
         // is why the sample rate is stored in an Arc<AtomicF32> which we can
@@ -148,7 +721,25 @@ impl Plugin for NihFaustJit {
 
         let selected_paths_arc = Arc::clone(&self.params.selected_paths);
         let dsp_nvoices_arc = Arc::clone(&self.params.dsp_nvoices);
+        let dsp_voice_policy_arc = Arc::clone(&self.params.dsp_voice_policy);
+        let dsp_voice_release_arc = Arc::clone(&self.params.dsp_voice_release);
+        let dsp_group_voices_arc = Arc::clone(&self.params.dsp_group_voices);
+        let dsp_mpe_arc = Arc::clone(&self.params.dsp_mpe);
         let dsp_state_arc = Arc::clone(&self.dsp_state);
+        let reload_error_arc = Arc::clone(&self.reload_error);
+        let load_status_arc = Arc::clone(&self.load_status);
+        let compile_requester = self.compile_requester;
+        let compile_helper_arc = Arc::clone(&self.params.compile_helper);
+        let fading_dsp_arc = Arc::clone(&self.fading_dsp);
+        let swap_fade_ms_arc = Arc::clone(&self.params.swap_fade_ms);
+        let tuning_paths_arc = Arc::clone(&self.params.tuning_paths);
+        let tuning_error_arc = Arc::clone(&self.tuning_error);
+        let midi_transform_arc = Arc::clone(&self.params.midi_transform);
+        let cc_mappings_arc = Arc::clone(&self.params.cc_mappings);
+        let slots_state_arc = Arc::clone(&self.params.slots_state);
+        let slot_assignment_arc = Arc::clone(&self.params.slot_assignment);
+        // The sample rate and layout the current DSP was loaded for:
+        let loaded_for_arc = Arc::new(RwLock::new(None::<(f32, AudioIOLayout)>));
 
         let cache_folder = env!("LLVM_CACHE_FOLDER"); // Build-time env var
         let opt_cache = if cache_folder.is_empty() {
@@ -161,50 +752,182 @@ impl Plugin for NihFaustJit {
         Box::new(move |task| match task {
             Tasks::ReloadDsp => {
                 let sample_rate = sample_rate_arc.load(Ordering::Relaxed);
-                let selected_paths = selected_paths_arc.read().unwrap();
+                // Not keeping the paths locked while compiling, so that the GUI
+                // doesn't freeze:
+                let (dsp_script, dsp_lib_path) = {
+                    let selected_paths = selected_paths_arc.read().unwrap();
+                    (
+                        selected_paths.dsp_script.clone(),
+                        selected_paths.dsp_lib_path.clone(),
+                    )
+                };
                 let dsp_nvoices = *dsp_nvoices_arc.read().unwrap();
-                let new_dsp_state = match &selected_paths.dsp_script {
+                let mut load_mode = faust_jit::DspLoadMode::from_nvoices(dsp_nvoices);
+                if let faust_jit::DspLoadMode::Instrument {
+                    voice_policy,
+                    release,
+                    group_voices,
+                    mpe,
+                    ..
+                } = &mut load_mode
+                {
+                    *voice_policy = dsp_voice_policy_arc.read().unwrap().to_policy();
+                    *release = dsp_voice_release_arc.read().unwrap().to_release();
+                    *group_voices = *dsp_group_voices_arc.read().unwrap();
+                    *mpe = dsp_mpe_arc.read().unwrap().to_config();
+                }
+                let new_dsp_state = match &dsp_script {
                     Some(script_path) => {
-                        match faust_jit::SingletonDsp::from_file(
-                            opt_cache.as_ref(),
-                            script_path,
-                            &[&selected_paths.dsp_lib_path],
-                            sample_rate as i32,
-                            &faust_jit::DspLoadMode::from_nvoices(dsp_nvoices),
-                        ) {
+                        let mut started = Instant::now();
+                        let compile_helper = compile_helper_arc.read().unwrap().to_helper();
+                        let loaded = match compile_helper {
+                            Err(msg) => Some(Err(msg)),
+                            Ok(opt_helper) => faust_jit::SingletonDsp::from_file_queued(
+                                opt_cache.as_ref(),
+                                opt_helper.as_ref(),
+                                script_path,
+                                &[&dsp_lib_path],
+                                sample_rate as i32,
+                                &load_mode,
+                                compile_requester,
+                                |position| {
+                                    *load_status_arc.write().unwrap() = if position == 0 {
+                                        started = Instant::now();
+                                        LoadStatus::Compiling {
+                                            started,
+                                            script: script_path.clone(),
+                                        }
+                                    } else {
+                                        LoadStatus::Queued {
+                                            position,
+                                            script: script_path.clone(),
+                                        }
+                                    };
+                                },
+                            ),
+                        };
+                        let Some(loaded) = loaded else {
+                            // A more recent reload of this instance replaced this
+                            // one, and will update the state:
+                            log!(Level::Debug, "Reload of {:?} superseded", script_path);
+                            return;
+                        };
+                        let new_dsp_state = match loaded {
                             Err(msg) => DspState::Failed(msg),
-                            Ok(dsp) => {
-                                if dsp.info.num_inputs <= 2 && dsp.info.num_outputs <= 2 {
-                                    DspState::Loaded(dsp)
-                                } else {
-                                    DspState::Failed(
-                                        format!("DSP has {} input and {} output channels. Max is 2 for each", dsp.info.num_inputs, dsp.info.num_outputs)
-                                    )
-                                }
-                            }
-                        }
+                            Ok(dsp) => match check_dsp_channels(&dsp.info) {
+                                Ok(()) => DspState::Loaded(dsp),
+                                Err(msg) => DspState::Failed(msg),
+                            },
+                        };
+                        let duration = started.elapsed();
+                        let source = match &new_dsp_state {
+                            DspState::Loaded(dsp) => Some(dsp.factory_source),
+                            _ => None,
+                        };
+                        log!(
+                            Level::Info,
+                            "Loading {:?} took {:?} ({:?})",
+                            script_path,
+                            duration,
+                            source
+                        );
+                        *load_status_arc.write().unwrap() = LoadStatus::Done {
+                            script: script_path.clone(),
+                            duration,
+                            source,
+                        };
+                        new_dsp_state
+                    }
+                    None => {
+                        *load_status_arc.write().unwrap() = LoadStatus::Idle;
+                        DspState::NoDspScript
                     }
-                    None => DspState::NoDspScript,
                 };
+                // A script that fails to load doesn't replace a working DSP, so
+                // that e.g. a typo doesn't mute the track. Unless the DSP was
+                // loaded for another sample rate or layout, as it would then
+                // play out of tune or with the wrong channels:
+                let host_layout = *host_layout_arc.read().unwrap();
+                let unchanged = *loaded_for_arc.read().unwrap() == Some((sample_rate, host_layout));
+                if let (DspState::Failed(msg), true) = (&new_dsp_state, unchanged) {
+                    if let DspState::Loaded(_) = &*dsp_state_arc.read().unwrap() {
+                        log!(Level::Debug, "Reload failed, keeping the previous DSP");
+                        *reload_error_arc.write().unwrap() = Some(msg.clone());
+                        return;
+                    }
+                }
+                *reload_error_arc.write().unwrap() = None;
                 log!(
                     Level::Debug,
                     "Loaded {:?} with sample_rate={}, nvoices={} => {:?}",
-                    selected_paths,
+                    dsp_script,
                     sample_rate,
                     dsp_nvoices,
                     new_dsp_state
                 );
-                // This is the only place where the whole DSP state is locked in
-                // write mode, and only so we can swap it with the newly loaded
-                // one:
-                *dsp_state_arc.write().unwrap() = new_dsp_state;
+                let mut new_counts = None;
+                if let DspState::Loaded(dsp) = &new_dsp_state {
+                    *tuning_error_arc.write().unwrap() =
+                        tuning_paths_arc.read().unwrap().apply_to(dsp).err();
+                    dsp.set_midi_transform(midi_transform_arc.read().unwrap().to_transform());
+                    set_cc_mappings(dsp, &cc_mappings_arc.read().unwrap());
+                    new_counts = Some(routing::ChannelCounts::new(&host_layout, &dsp.info));
+                }
+                *loaded_for_arc.write().unwrap() = match &new_dsp_state {
+                    DspState::Loaded(_) => Some((sample_rate, host_layout)),
+                    _ => None,
+                };
+                let fade_length =
+                    (*swap_fade_ms_arc.read().unwrap() * sample_rate / 1000.0) as usize;
+                swap_dsp_state(
+                    &dsp_state_arc,
+                    &fading_dsp_arc,
+                    &routing_arc,
+                    new_dsp_state,
+                    new_counts,
+                    fade_length,
+                );
+                if let DspState::Loaded(dsp) = &*dsp_state_arc.read().unwrap() {
+                    slots_state_arc.reassign(dsp, &mut slot_assignment_arc.write().unwrap());
+                }
+            }
+            Tasks::ReloadTuning => {
+                if let DspState::Loaded(dsp) = &*dsp_state_arc.read().unwrap() {
+                    *tuning_error_arc.write().unwrap() =
+                        tuning_paths_arc.read().unwrap().apply_to(dsp).err();
+                }
+            }
+            Tasks::UnloadDsp => {
+                let sample_rate = sample_rate_arc.load(Ordering::Relaxed);
+                let fade_length =
+                    (*swap_fade_ms_arc.read().unwrap() * sample_rate / 1000.0) as usize;
+                *reload_error_arc.write().unwrap() = None;
+                *load_status_arc.write().unwrap() = LoadStatus::Idle;
+                *loaded_for_arc.write().unwrap() = None;
+                swap_dsp_state(
+                    &dsp_state_arc,
+                    &fading_dsp_arc,
+                    &routing_arc,
+                    DspState::Unloaded,
+                    None,
+                    fade_length,
+                );
+            }
+            Tasks::ReleaseFadedDsp => {
+                let mut fading_dsp = fading_dsp_arc.write().unwrap();
+                // Another fade may have started since the task was sent:
+                if fading_dsp.as_ref().is_some_and(FadingDsp::is_done) {
+                    let released = fading_dsp.take();
+                    drop(fading_dsp);
+                    drop(released);
+                }
             }
         })
     }
 
     fn initialize(
         &mut self,
-        _audio_io_layout: &AudioIOLayout,
+        audio_io_layout: &AudioIOLayout,
         buffer_config: &BufferConfig,
         init_ctx: &mut impl InitContext<Self>,
     ) -> bool {
@@ -213,6 +936,18 @@ impl Plugin for NihFaustJit {
         // function if you do not need it.
         self.sample_rate
             .store(buffer_config.sample_rate, Ordering::Relaxed);
+        *self.host_layout.write().unwrap() = *audio_io_layout;
+        self.router.resize(buffer_config.max_buffer_size as usize);
+        let main_channels = audio_io_layout
+            .main_output_channels
+            .map_or(0, |c| c.get() as usize);
+        self.dry_wet.resize(
+            main_channels,
+            buffer_config.sample_rate,
+            buffer_config.max_buffer_size as usize,
+        );
+        self.swap_fader
+            .resize(main_channels, buffer_config.max_buffer_size as usize);
         init_ctx.execute(Tasks::ReloadDsp);
         true
     }
@@ -233,10 +968,22 @@ impl Plugin for NihFaustJit {
     fn process(
         &mut self,
         buffer: &mut Buffer,
-        _aux: &mut AuxiliaryBuffers,
+        aux: &mut AuxiliaryBuffers,
         process_ctx: &mut impl ProcessContext<Self>,
     ) -> ProcessStatus {
-        if let DspState::Loaded(dsp) = &*self.dsp_state.read().unwrap() {
+        // The DSP that was just replaced, if it is still fading out:
+        let fading_dsp = self.fading_dsp.read().unwrap();
+        let fading = fading_dsp.as_ref().filter(|fading| !fading.is_done());
+        if fading.is_some() {
+            self.swap_fader.store_input(buffer.as_slice());
+        }
+        let sidechain = aux
+            .inputs
+            .iter()
+            .flat_map(|port| port.as_slice_immutable().iter().map(|chan| &**chan));
+
+        let dsp_state = self.dsp_state.read().unwrap();
+        if let DspState::Loaded(dsp) = &*dsp_state {
             // Handling transport & clock:
             let tp = process_ctx.transport();
             let opt_clock_data = match (tp.tempo, tp.pos_samples()) {
@@ -248,37 +995,280 @@ impl Plugin for NihFaustJit {
                 _ => None,
             };
             dsp.handle_midi_sync(tp.playing, &opt_clock_data);
+            if self.was_playing && !tp.playing {
+                dsp.all_notes_off(false);
+            }
+            self.was_playing = tp.playing;
+            dsp.handle_transport(&faust_jit::TransportData {
+                playing: tp.playing,
+                tempo: tp.tempo,
+                pos_beats: tp.pos_beats(),
+                bar_number: tp.bar_number(),
+                bar_start_pos_beats: tp.bar_start_pos_beats(),
+                time_sig: tp.time_sig_numerator.zip(tp.time_sig_denominator),
+                next_buffer_size: buffer.samples(),
+            });
+
+            // Forwarding the automation of the parameter slots:
+            param_slots::apply_slots(
+                dsp,
+                &self.params.slots,
+                &self.params.slots_state,
+                &mut self.last_slot_values,
+            );
+            self.poly_modulations
+                .apply(dsp, &self.params.slots, &self.params.slots_state);
 
             // Handling MIDI events:
             while let Some(midi_event) = process_ctx.next_event() {
                 let time = midi_event.timing() as f64;
+                let poly_mod = |(voice_id, slot, offset), timing| {
+                    self.poly_modulations.set(
+                        dsp,
+                        &self.params.slots,
+                        &self.params.slots_state,
+                        (voice_id, slot, offset),
+                        timing,
+                    )
+                };
+                if handle_voice_event(dsp, &midi_event, process_ctx, poly_mod) {
+                    continue;
+                }
                 match midi_event.as_midi() {
-                    None | Some(MidiResult::SysEx(_, _)) => { /* We ignore SysEx messages */ }
-                    Some(MidiResult::Basic(bytes)) => dsp.handle_raw_midi(time, bytes),
+                    None => {}
+                    Some(MidiResult::Basic(bytes)) => {
+                        // nih_plug always gives 3 bytes, even for shorter messages
+                        let len = faust_jit::midi_message_len(bytes[0]).unwrap_or(3);
+                        dsp.handle_raw_midi(time, &bytes[..len])
+                    }
+                    Some(MidiResult::SysEx(buffer, len)) => {
+                        dsp.handle_raw_midi(time, &buffer[..len])
+                    }
                 }
             }
 
             // Processing audio buffers:
-            dsp.process_buffers(buffer.as_slice());
-        }
-        // Applying Gain parameter:
-        for channel_samples in buffer.iter_samples() {
-            let gain = self.params.gain.smoothed.next();
+            // The dry signal can't be delayed more than that, so neither can
+            // the host compensate more:
+            let latency = (dsp.info.latency as u32).min(self.dry_wet.max_latency() as u32);
+            let voice_capacity = (dsp.num_voices() as u32).clamp(1, MAX_VOICES as u32);
+            if voice_capacity != self.reported_voice_capacity {
+                process_ctx.set_current_voice_capacity(voice_capacity);
+                self.reported_voice_capacity = voice_capacity;
+            }
+            if latency != self.reported_latency {
+                process_ctx.set_latency_samples(latency);
+                self.reported_latency = latency;
+            }
+            self.dry_wet.store_dry(buffer, latency as usize);
+            self.router.process(
+                dsp,
+                &self.params.routing.read().unwrap(),
+                buffer.as_slice(),
+                sidechain.clone(),
+            );
+            if let Some(fading) = fading {
+                self.swap_fader.fade(fading, buffer.as_slice(), sidechain);
+            }
+            self.dry_wet.mix(
+                buffer,
+                &self.params.mix,
+                &self.params.gain,
+                self.params.bypass.value(),
+                self.sample_rate.load(Ordering::Relaxed),
+            );
+
+            // Telling the host which voices ended:
+            let last_sample = buffer.samples().saturating_sub(1) as u32;
+            dsp.take_terminated_notes(|note| {
+                if let Some(voice_id) = note.note_id {
+                    self.poly_modulations.voice_terminated(voice_id);
+                }
+                process_ctx.send_event(NoteEvent::VoiceTerminated {
+                    timing: last_sample,
+                    voice_id: note.note_id,
+                    channel: note.channel,
+                    note: note.note,
+                });
+            });
 
-            for sample in channel_samples {
-                *sample *= gain;
+            // Forwarding the MIDI messages sent by the DSP:
+            dsp.take_midi_output(|time, bytes| {
+                if let Ok(event) = NoteEvent::from_midi(time as u32, bytes) {
+                    process_ctx.send_event(event);
+                }
+            });
+        } else {
+            if let DspState::Unloaded = &*dsp_state {
+                for channel in buffer.as_slice() {
+                    channel.fill(0.0);
+                }
             }
+            if let Some(fading) = fading {
+                self.swap_fader.fade(fading, buffer.as_slice(), sidechain);
+            }
+            if self.reported_latency != 0 {
+                process_ctx.set_latency_samples(0);
+                self.reported_latency = 0;
+            }
+            self.dry_wet.apply_gain(
+                buffer,
+                &self.params.gain,
+                self.params.bypass.value(),
+                self.sample_rate.load(Ordering::Relaxed),
+            );
+        }
+        drop(dsp_state);
+        let release_faded_dsp = fading_dsp.as_ref().is_some_and(FadingDsp::should_release);
+        drop(fading_dsp);
+        if release_faded_dsp {
+            process_ctx.execute_background(Tasks::ReleaseFadedDsp);
         }
         ProcessStatus::Normal
     }
 }
 
+/// Sends to the DSP the note events that target specific voices. Returns false
+/// if `event` is not one of them. Polyphonic modulations are given to
+/// `poly_mod`, as (voice id, slot, normalized offset) along with their timing
+fn handle_voice_event(
+    dsp: &faust_jit::SingletonDsp,
+    event: &NoteEvent<RawSysEx>,
+    process_ctx: &mut impl ProcessContext<NihFaustJit>,
+    mut poly_mod: impl FnMut((i32, usize, f32), usize),
+) -> bool {
+    use faust_jit::NoteExpression as NE;
+    let (voice_id, channel, note, expression, value) = match *event {
+        NoteEvent::NoteOn {
+            timing,
+            voice_id,
+            channel,
+            note,
+            velocity,
+        } => {
+            let note = faust_jit::NoteRef {
+                note_id: voice_id,
+                channel,
+                note,
+            };
+            if !dsp.handle_note_on(timing as f64, note, (velocity * 127.0).round() as u8) {
+                // The note was dropped by the MIDI transform, so no voice will
+                // tell the host when it ends:
+                process_ctx.send_event(NoteEvent::VoiceTerminated {
+                    timing,
+                    voice_id,
+                    channel,
+                    note: note.note,
+                });
+            }
+            return true;
+        }
+        NoteEvent::NoteOff {
+            timing,
+            voice_id,
+            channel,
+            note,
+            velocity,
+        } => {
+            let note = faust_jit::NoteRef {
+                note_id: voice_id,
+                channel,
+                note,
+            };
+            dsp.handle_note_off(timing as f64, note, (velocity * 127.0).round() as u8);
+            return true;
+        }
+        NoteEvent::PolyPressure {
+            voice_id,
+            channel,
+            note,
+            pressure,
+            ..
+        } => (voice_id, channel, note, NE::Pressure, pressure),
+        NoteEvent::PolyVolume {
+            voice_id,
+            channel,
+            note,
+            gain,
+            ..
+        } => (voice_id, channel, note, NE::Volume, gain),
+        NoteEvent::PolyPan {
+            voice_id,
+            channel,
+            note,
+            pan,
+            ..
+        } => (voice_id, channel, note, NE::Pan, (pan + 1.0) / 2.0),
+        NoteEvent::PolyTuning {
+            voice_id,
+            channel,
+            note,
+            tuning,
+            ..
+        } => (voice_id, channel, note, NE::Tuning, tuning),
+        NoteEvent::PolyVibrato {
+            voice_id,
+            channel,
+            note,
+            vibrato,
+            ..
+        } => (voice_id, channel, note, NE::Vibrato, vibrato),
+        NoteEvent::PolyExpression {
+            voice_id,
+            channel,
+            note,
+            expression,
+            ..
+        } => (voice_id, channel, note, NE::Expression, expression),
+        NoteEvent::PolyBrightness {
+            voice_id,
+            channel,
+            note,
+            brightness,
+            ..
+        } => (voice_id, channel, note, NE::Timbre, brightness),
+        // Only the parameter slots can be modulated per voice (Gain is applied
+        // after the voices are mixed):
+        NoteEvent::PolyModulation {
+            timing,
+            voice_id,
+            poly_modulation_id,
+            normalized_offset,
+        } => {
+            poly_mod(
+                (voice_id, poly_modulation_id as usize, normalized_offset),
+                timing as usize,
+            );
+            return true;
+        }
+        // The slots' values are already updated by nih_plug, and the offsets
+        // are applied again before each buffer:
+        NoteEvent::MonoAutomation { .. } => return true,
+        _ => return false,
+    };
+    let note = faust_jit::NoteRef {
+        note_id: voice_id,
+        channel,
+        note,
+    };
+    dsp.set_note_expression(note, expression, value);
+    true
+}
+
 impl ClapPlugin for NihFaustJit {
     const CLAP_ID: &'static str = "com.ypares.nih-faust-jit";
     const CLAP_DESCRIPTION: Option<&'static str> = Some("Using jit-compiled Faust DSP scripts");
     const CLAP_MANUAL_URL: Option<&'static str> = Some(Self::URL);
     const CLAP_SUPPORT_URL: Option<&'static str> = None;
 
+    // Lets the host know that we track voices, so it can send per-voice note
+    // expressions and modulate the parameter slots per voice. The current
+    // capacity is reported once the DSP is loaded
+    const CLAP_POLY_MODULATION_CONFIG: Option<PolyModulationConfig> = Some(PolyModulationConfig {
+        max_voice_capacity: MAX_VOICES as u32,
+        supports_overlapping_voices: true,
+    });
+
     // Don't forget to change these features
     const CLAP_FEATURES: &'static [ClapFeature] = &[
         ClapFeature::AudioEffect,
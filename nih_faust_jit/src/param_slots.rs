@@ -0,0 +1,347 @@
+//! Generic host parameters that can be assigned to the widgets of the DSP, so
+//! that the host can automate them
+//!
+//! The parameters a plugin exposes to the host cannot change once the plugin
+//! is loaded, so there is a fixed number of slots, whose values are normalized
+//! (between 0 and 1) and mapped to the range of the widget they are assigned
+//! to. The host shows the label, value and unit of that widget.
+//!
+//! Hosts supporting CLAP polyphonic modulation can also offset the slots for
+//! a single voice, when their widget is one of the widgets controlling all the
+//! voices of an instrument loaded with ungrouped voices.
+
+use nih_plug::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc, RwLock,
+};
+
+use faust_jit::{for_each_widget, DspWidget, NoteRef, SingletonDsp};
+
+/// Must not exceed 64, as some slot flags are stored in a u64
+pub const NUM_PARAM_SLOTS: usize = 64;
+
+#[derive(Params)]
+pub struct SlotParams {
+    #[id = "slot"]
+    pub value: FloatParam,
+}
+
+impl SlotParams {
+    fn new(index: usize, state: Arc<SlotsState>) -> Self {
+        Self {
+            value: FloatParam::new(
+                format!("Slot {}", index + 1),
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_poly_modulation_id(index as u32)
+            .with_value_to_string(Arc::new(move |value| {
+                match &state.targets.read().unwrap()[index] {
+                    Some(target) => target.value_to_string(value),
+                    None => format!("(unassigned) {:.2}", value),
+                }
+            })),
+        }
+    }
+}
+
+/// Builds all the slots, which will all describe their values according to
+/// `state`
+pub fn new_slots(state: &Arc<SlotsState>) -> [SlotParams; NUM_PARAM_SLOTS] {
+    std::array::from_fn(|i| SlotParams::new(i, Arc::clone(state)))
+}
+
+/// A widget that can be assigned to a slot
+#[derive(Debug, Clone)]
+pub struct SlotTarget {
+    /// The full path of the widget (see [`faust_jit::ParamRef::Path`])
+    pub path: String,
+    label: String,
+    min: f32,
+    max: f32,
+    unit: Option<String>,
+    /// Whether the widget is a button or a checkbox
+    toggle: bool,
+}
+
+impl SlotTarget {
+    /// The value of the widget for a given value of the slot
+    fn value(&self, slot_value: f32) -> f32 {
+        if self.toggle {
+            slot_value.round()
+        } else {
+            self.min + slot_value * (self.max - self.min)
+        }
+    }
+
+    fn value_to_string(&self, slot_value: f32) -> String {
+        let value = self.value(slot_value);
+        if self.toggle {
+            format!(
+                "{}: {}",
+                self.label,
+                if value != 0.0 { "on" } else { "off" }
+            )
+        } else {
+            format!(
+                "{}: {:.2}{}",
+                self.label,
+                value,
+                self.unit.as_deref().unwrap_or("")
+            )
+        }
+    }
+}
+
+/// Lists the widgets of a DSP that can be assigned to a slot, in the order of
+/// the UI
+pub fn slot_targets(dsp: &SingletonDsp) -> Vec<SlotTarget> {
+    let mut targets = vec![];
+    dsp.with_widgets(|widgets| {
+        for_each_widget(widgets, |path, widget| {
+            let target = |min, max, unit: &Option<String>, toggle| SlotTarget {
+                path: path.to_owned(),
+                label: widget.label().to_owned(),
+                min,
+                max,
+                unit: unit.clone(),
+                toggle,
+            };
+            match widget {
+                DspWidget::BoolParam { hidden: false, .. } => {
+                    targets.push(target(0.0, 1.0, &None, true))
+                }
+                DspWidget::NumParam {
+                    min, max, metadata, ..
+                } if !metadata.hidden => targets.push(target(*min, *max, &metadata.unit, false)),
+                _ => {}
+            }
+        })
+    });
+    targets
+}
+
+/// Which widget each slot is assigned to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SlotAssignment {
+    /// Whether the slots are assigned to the first widgets of the DSP (in the
+    /// order of the UI) each time it is loaded
+    pub auto: bool,
+    /// The path of the widget assigned to each slot
+    pub paths: Vec<Option<String>>,
+}
+
+impl Default for SlotAssignment {
+    fn default() -> Self {
+        Self {
+            auto: true,
+            paths: vec![None; NUM_PARAM_SLOTS],
+        }
+    }
+}
+
+/// What the audio thread, the GUI and the background tasks share about the
+/// slots, besides their (persisted) assignment
+#[derive(Debug)]
+pub struct SlotsState {
+    /// The widget assigned to each slot, if it exists in the current DSP
+    targets: RwLock<Vec<Option<SlotTarget>>>,
+    /// Bit N is set once slot N has been changed by the host since it was
+    /// assigned. A slot drives its widget only after that, so that assigning a
+    /// widget doesn't reset it to the (arbitrary) value of the slot. Persisted,
+    /// so that the values of the slots are applied again when a project is
+    /// reloaded
+    pub touched: Arc<AtomicU64>,
+    /// Set when the DSP or the assignment changes, so that the audio thread
+    /// applies again the values of the (touched) slots
+    dirty: AtomicBool,
+}
+
+impl Default for SlotsState {
+    fn default() -> Self {
+        Self {
+            targets: RwLock::new(vec![None; NUM_PARAM_SLOTS]),
+            touched: Arc::new(AtomicU64::new(0)),
+            // The values of the slots have not been read yet:
+            dirty: AtomicBool::new(true),
+        }
+    }
+}
+
+impl SlotsState {
+    /// Assigns the slots again for a newly loaded DSP, automatically if the
+    /// assignment says so. Slots that end up assigned to another widget than
+    /// before are untouched
+    pub fn reassign(&self, dsp: &SingletonDsp, assignment: &mut SlotAssignment) {
+        let available = slot_targets(dsp);
+        if assignment.auto {
+            let new_paths = (0..NUM_PARAM_SLOTS).map(|i| available.get(i).map(|t| t.path.clone()));
+            for (i, new_path) in new_paths.enumerate() {
+                if assignment.paths[i] != new_path {
+                    self.touched.fetch_and(!(1 << i), Ordering::Relaxed);
+                    assignment.paths[i] = new_path;
+                }
+            }
+        }
+        self.resolve(&available, assignment);
+    }
+
+    /// Assigns one slot to a widget (or to nothing), and disables automatic
+    /// assignment
+    pub fn assign(
+        &self,
+        dsp: &SingletonDsp,
+        assignment: &mut SlotAssignment,
+        slot: usize,
+        path: Option<String>,
+    ) {
+        assignment.auto = false;
+        assignment.paths[slot] = path;
+        self.touched.fetch_and(!(1 << slot), Ordering::Relaxed);
+        self.resolve(&slot_targets(dsp), assignment);
+    }
+
+    fn resolve(&self, available: &[SlotTarget], assignment: &SlotAssignment) {
+        let mut targets = self.targets.write().unwrap();
+        for (target, path) in targets.iter_mut().zip(&assignment.paths) {
+            *target = path
+                .as_ref()
+                .and_then(|path| available.iter().find(|t| &t.path == path).cloned());
+        }
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// The widget currently assigned to a slot
+    pub fn target(&self, slot: usize) -> Option<SlotTarget> {
+        self.targets.read().unwrap()[slot].clone()
+    }
+}
+
+/// Forwards to the DSP the slots changed by the host. Called by the audio
+/// thread before each (sub-)buffer, so automation is sample-accurate. Does not
+/// allocate
+pub fn apply_slots(
+    dsp: &SingletonDsp,
+    slots: &[SlotParams; NUM_PARAM_SLOTS],
+    state: &SlotsState,
+    last_values: &mut [f32; NUM_PARAM_SLOTS],
+) {
+    // Assignment changes are rare, so we just wait for the next buffer if one
+    // is underway:
+    let Ok(targets) = state.targets.try_read() else {
+        return;
+    };
+    let dirty = state.dirty.swap(false, Ordering::Relaxed);
+    let mut newly_touched = 0;
+    let touched = state.touched.load(Ordering::Relaxed);
+    for (i, slot) in slots.iter().enumerate() {
+        let value = slot.value.value();
+        let changed = value != last_values[i];
+        last_values[i] = value;
+        // When dirty, the values may differ only because they were never read
+        // before:
+        if changed && !dirty {
+            newly_touched |= 1 << i;
+        }
+        let is_touched = (touched | newly_touched) & (1 << i) != 0;
+        if let (Some(target), true) = (&targets[i], is_touched && (changed || dirty)) {
+            dsp.schedule_param(target.path.as_str(), target.value(value), 0);
+        }
+    }
+    if newly_touched != 0 {
+        state.touched.fetch_or(newly_touched, Ordering::Relaxed);
+    }
+}
+
+/// The offsets of the slots for single voices, sent by the host. Only used by
+/// the audio thread
+pub struct PolyModulations {
+    /// (voice id, slot, normalized offset), never reallocated
+    offsets: Vec<(i32, usize, f32)>,
+}
+
+impl Default for PolyModulations {
+    fn default() -> Self {
+        Self {
+            offsets: Vec::with_capacity(Self::CAPACITY),
+        }
+    }
+}
+
+impl PolyModulations {
+    const CAPACITY: usize = 1024;
+
+    /// Changes the offset of a slot for a voice, and applies it. Does not
+    /// allocate
+    pub fn set(
+        &mut self,
+        dsp: &SingletonDsp,
+        slots: &[SlotParams; NUM_PARAM_SLOTS],
+        state: &SlotsState,
+        (voice_id, slot, offset): (i32, usize, f32),
+        timing: usize,
+    ) {
+        if slot >= NUM_PARAM_SLOTS {
+            return;
+        }
+        match self
+            .offsets
+            .iter_mut()
+            .find(|(v, s, _)| (*v, *s) == (voice_id, slot))
+        {
+            Some(entry) => entry.2 = offset,
+            None => {
+                if self.offsets.len() == Self::CAPACITY {
+                    self.offsets.remove(0);
+                }
+                self.offsets.push((voice_id, slot, offset));
+            }
+        }
+        if let Ok(targets) = state.targets.try_read() {
+            Self::apply_one(dsp, slots, &targets, (voice_id, slot, offset), timing);
+        }
+    }
+
+    /// Forgets the offsets of a voice that ended
+    pub fn voice_terminated(&mut self, voice_id: i32) {
+        self.offsets.retain(|(v, _, _)| *v != voice_id);
+    }
+
+    /// Applies again all the offsets. Called by the audio thread before each
+    /// buffer, as the voices get the value of the slot again when it changes
+    /// or when its widget is changed from the GUI. Does not allocate
+    pub fn apply(
+        &self,
+        dsp: &SingletonDsp,
+        slots: &[SlotParams; NUM_PARAM_SLOTS],
+        state: &SlotsState,
+    ) {
+        let Ok(targets) = state.targets.try_read() else {
+            return;
+        };
+        for &offset in &self.offsets {
+            Self::apply_one(dsp, slots, &targets, offset, 0);
+        }
+    }
+
+    fn apply_one(
+        dsp: &SingletonDsp,
+        slots: &[SlotParams; NUM_PARAM_SLOTS],
+        targets: &[Option<SlotTarget>],
+        (voice_id, slot, offset): (i32, usize, f32),
+        timing: usize,
+    ) {
+        let Some(target) = &targets[slot] else {
+            return;
+        };
+        let note = NoteRef {
+            note_id: Some(voice_id),
+            channel: 0,
+            note: 0,
+        };
+        let value = (slots[slot].value.value() + offset).clamp(0.0, 1.0);
+        dsp.schedule_voice_param(note, target.path.as_str(), target.value(value), timing);
+    }
+}
@@ -0,0 +1,299 @@
+//! Routing of the host's channels to the DSP's, so that a DSP can be used
+//! whatever its number of inputs and outputs
+
+use nih_plug::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// The max number of inputs or outputs of a DSP
+pub const MAX_DSP_CHANNELS: usize = 32;
+
+/// Gains from the host inputs (the main ones, then the sidechain ones) to the
+/// DSP inputs, and from the DSP outputs to the host outputs
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoutingMatrix {
+    /// Whether the matrix is reset to the default one (see [`Self::default_for`])
+    /// each time a DSP is loaded or the host changes the layout
+    pub auto: bool,
+    /// `inputs[i][h]` is the gain from host input `h` to DSP input `i`
+    pub inputs: Vec<Vec<f32>>,
+    /// `outputs[o][d]` is the gain from DSP output `d` to host output `o`
+    pub outputs: Vec<Vec<f32>>,
+}
+
+impl Default for RoutingMatrix {
+    fn default() -> Self {
+        Self {
+            auto: true,
+            inputs: vec![],
+            outputs: vec![],
+        }
+    }
+}
+
+/// How many channels the host and the DSP have
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChannelCounts {
+    pub host_main: usize,
+    pub host_sidechain: usize,
+    pub dsp_inputs: usize,
+    pub dsp_outputs: usize,
+}
+
+impl ChannelCounts {
+    pub fn new(layout: &AudioIOLayout, info: &faust_jit::DspInfo) -> Self {
+        Self {
+            host_main: layout.main_output_channels.map_or(0, |c| c.get() as usize),
+            host_sidechain: layout
+                .aux_input_ports
+                .iter()
+                .map(|c| c.get() as usize)
+                .sum(),
+            dsp_inputs: info.num_inputs as usize,
+            dsp_outputs: info.num_outputs as usize,
+        }
+    }
+}
+
+impl RoutingMatrix {
+    /// The default routing:
+    ///
+    /// - a mono DSP gets the sum of the main inputs (downmixed), and its output
+    ///   goes to all the host outputs (dual-mono)
+    /// - else DSP input N gets host input N, so the inputs beyond the main ones
+    ///   are read from the sidechain. E.g. a DSP with 4 inputs in the stereo
+    ///   layout gets the main signal on its inputs 0 and 1 and the sidechain
+    ///   (key) signal on its inputs 2 and 3
+    /// - and DSP output N goes to host output N modulo the number of host
+    ///   outputs, so extra outputs are downmixed (e.g. the even outputs of a 6
+    ///   outputs DSP to the left and the odd ones to the right)
+    pub fn default_for(counts: ChannelCounts) -> Self {
+        let host_inputs = counts.host_main + counts.host_sidechain;
+        let inputs = (0..counts.dsp_inputs)
+            .map(|i| {
+                (0..host_inputs)
+                    .map(|h| match counts.dsp_inputs {
+                        1 if h < counts.host_main => 1.0 / counts.host_main as f32,
+                        1 => 0.0,
+                        _ if h == i => 1.0,
+                        _ => 0.0,
+                    })
+                    .collect()
+            })
+            .collect();
+        let downmix_gain = match counts.host_main {
+            0 => 0.0,
+            n => 1.0 / counts.dsp_outputs.div_ceil(n) as f32,
+        };
+        let outputs = (0..counts.host_main)
+            .map(|o| {
+                (0..counts.dsp_outputs)
+                    .map(|d| match counts.dsp_outputs {
+                        1 => 1.0,
+                        _ if d % counts.host_main == o => downmix_gain,
+                        _ => 0.0,
+                    })
+                    .collect()
+            })
+            .collect();
+        Self {
+            auto: true,
+            inputs,
+            outputs,
+        }
+    }
+
+    /// Makes the matrix match new channel counts: resets it to the default one
+    /// if it is automatic or if its size doesn't match
+    pub fn fit(&mut self, counts: ChannelCounts) {
+        let host_inputs = counts.host_main + counts.host_sidechain;
+        let fits = self.inputs.len() == counts.dsp_inputs
+            && self.inputs.iter().all(|row| row.len() == host_inputs)
+            && self.outputs.len() == counts.host_main
+            && self
+                .outputs
+                .iter()
+                .all(|row| row.len() == counts.dsp_outputs);
+        if self.auto || !fits {
+            *self = Self::default_for(counts);
+        }
+    }
+}
+
+/// The buffers given to the DSP
+#[derive(Debug, Default)]
+pub struct Router {
+    scratch: Vec<Vec<f32>>,
+}
+
+impl Router {
+    /// Allocates the buffers. Not to be called from the audio thread
+    pub fn resize(&mut self, max_buffer_size: usize) {
+        self.scratch = vec![vec![0.0; max_buffer_size]; MAX_DSP_CHANNELS];
+    }
+
+    /// Mixes the host inputs (`main` and then `sidechain`) into the DSP inputs,
+    /// processes them, and mixes the DSP outputs into `main`. Does not allocate
+    pub fn process<'a>(
+        &mut self,
+        dsp: &faust_jit::SingletonDsp,
+        matrix: &RoutingMatrix,
+        main: &mut [&mut [f32]],
+        sidechain: impl Iterator<Item = &'a [f32]> + Clone,
+    ) {
+        let samples = main.first().map_or(0, |chan| chan.len());
+        let dsp_chans =
+            (dsp.info.num_inputs.max(dsp.info.num_outputs).max(1) as usize).min(self.scratch.len());
+        if self.scratch.first().map_or(0, Vec::len) < samples {
+            // Not initialized for this buffer size:
+            return;
+        }
+
+        self.mix_inputs(matrix, dsp_chans, main, sidechain);
+        {
+            let mut chans: [&mut [f32]; MAX_DSP_CHANNELS] = std::array::from_fn(|_| &mut [][..]);
+            for (chan, scratch) in chans.iter_mut().zip(&mut self.scratch[..dsp_chans]) {
+                *chan = &mut scratch[..samples];
+            }
+            dsp.process_buffers(&mut chans[..dsp_chans]);
+        }
+        self.mix_outputs(matrix, (dsp.info.num_outputs as usize).min(dsp_chans), main);
+    }
+
+    /// Mixes the host inputs into the first `dsp_chans` buffers
+    fn mix_inputs<'a>(
+        &mut self,
+        matrix: &RoutingMatrix,
+        dsp_chans: usize,
+        main: &[&mut [f32]],
+        sidechain: impl Iterator<Item = &'a [f32]> + Clone,
+    ) {
+        let samples = main.first().map_or(0, |chan| chan.len());
+        for (i, scratch) in self.scratch[..dsp_chans].iter_mut().enumerate() {
+            let scratch = &mut scratch[..samples];
+            scratch.fill(0.0);
+            let Some(gains) = matrix.inputs.get(i) else {
+                continue;
+            };
+            let (main_gains, sidechain_gains) = gains.split_at(gains.len().min(main.len()));
+            for (&gain, host_input) in main_gains.iter().zip(main.iter()) {
+                add_scaled(scratch, gain, host_input);
+            }
+            for (&gain, host_input) in sidechain_gains.iter().zip(sidechain.clone()) {
+                add_scaled(scratch, gain, host_input);
+            }
+        }
+    }
+
+    /// Mixes the first `dsp_outputs` buffers into the host outputs
+    fn mix_outputs(&self, matrix: &RoutingMatrix, dsp_outputs: usize, main: &mut [&mut [f32]]) {
+        let dsp_outputs = &self.scratch[..dsp_outputs];
+        for (o, host_output) in main.iter_mut().enumerate() {
+            let samples = host_output.len();
+            host_output.fill(0.0);
+            let Some(gains) = matrix.outputs.get(o) else {
+                continue;
+            };
+            for (&gain, dsp_output) in gains.iter().zip(dsp_outputs) {
+                add_scaled(host_output, gain, &dsp_output[..samples]);
+            }
+        }
+    }
+}
+
+/// Adds `source` times `gain` to `dest`
+fn add_scaled(dest: &mut [f32], gain: f32, source: &[f32]) {
+    if gain != 0.0 {
+        for (d, x) in dest.iter_mut().zip(source) {
+            *d += gain * x;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counts(
+        host_main: usize,
+        host_sidechain: usize,
+        dsp_inputs: usize,
+        dsp_outputs: usize,
+    ) -> ChannelCounts {
+        ChannelCounts {
+            host_main,
+            host_sidechain,
+            dsp_inputs,
+            dsp_outputs,
+        }
+    }
+
+    #[test]
+    fn mono_dsp_in_stereo() {
+        let matrix = RoutingMatrix::default_for(counts(2, 0, 1, 1));
+        assert_eq!(matrix.inputs, vec![vec![0.5, 0.5]]);
+        assert_eq!(matrix.outputs, vec![vec![1.0], vec![1.0]]);
+    }
+
+    #[test]
+    fn six_outputs_are_downmixed_to_stereo() {
+        let matrix = RoutingMatrix::default_for(counts(2, 0, 0, 6));
+        let third = 1.0 / 3.0;
+        assert_eq!(
+            matrix.outputs,
+            vec![
+                vec![third, 0.0, third, 0.0, third, 0.0],
+                vec![0.0, third, 0.0, third, 0.0, third],
+            ]
+        );
+        // Rounded up when the outputs don't split evenly:
+        let matrix = RoutingMatrix::default_for(counts(2, 0, 0, 3));
+        assert_eq!(matrix.outputs[0], vec![0.5, 0.0, 0.5]);
+        assert_eq!(matrix.outputs[1], vec![0.0, 0.5, 0.0]);
+    }
+
+    #[test]
+    fn extra_inputs_read_the_sidechain() {
+        let matrix = RoutingMatrix::default_for(counts(2, 2, 4, 2));
+        let mut router = Router::default();
+        router.resize(4);
+        let (mut left, mut right) = ([1.0; 4], [2.0; 4]);
+        let (key_left, key_right) = ([3.0; 4], [4.0; 4]);
+        let main = [&mut left[..], &mut right[..]];
+        router.mix_inputs(
+            &matrix,
+            4,
+            &main,
+            [&key_left[..], &key_right[..]].into_iter(),
+        );
+        for (i, scratch) in router.scratch[..4].iter().enumerate() {
+            assert_eq!(scratch[..4], [i as f32 + 1.0; 4]);
+        }
+
+        router.scratch[0][..4].copy_from_slice(&[0.5; 4]);
+        router.scratch[1][..4].copy_from_slice(&[0.25; 4]);
+        let mut main = [&mut left[..], &mut right[..]];
+        router.mix_outputs(&matrix, 2, &mut main);
+        assert_eq!(left, [0.5; 4]);
+        assert_eq!(right, [0.25; 4]);
+    }
+
+    #[test]
+    fn fit_keeps_a_manual_matrix_of_the_right_size() {
+        let mut matrix = RoutingMatrix::default_for(counts(2, 0, 2, 2));
+        matrix.auto = false;
+        matrix.inputs[0] = vec![0.0, 1.0];
+        let manual = matrix.clone();
+        matrix.fit(counts(2, 0, 2, 2));
+        assert_eq!(matrix, manual);
+
+        // Reset when the size changes:
+        matrix.fit(counts(2, 0, 1, 2));
+        assert_eq!(matrix, RoutingMatrix::default_for(counts(2, 0, 1, 2)));
+        assert!(matrix.auto);
+
+        // And always when automatic:
+        matrix.inputs[0] = vec![1.0, 0.0];
+        matrix.fit(counts(2, 0, 1, 2));
+        assert_eq!(matrix, RoutingMatrix::default_for(counts(2, 0, 1, 2)));
+    }
+}
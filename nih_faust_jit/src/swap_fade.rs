@@ -0,0 +1,109 @@
+//! Crossfading from a DSP to the one that replaces it, so that reloading a
+//! script while the audio plays doesn't click
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::routing::{Router, RoutingMatrix};
+
+/// A DSP that was just replaced, and that the audio thread fades out. It is
+/// then dropped by a background task, so that it isn't deallocated by the
+/// audio thread
+#[derive(Debug)]
+pub struct FadingDsp {
+    dsp: faust_jit::SingletonDsp,
+    /// The routing that was used with this DSP
+    routing: RoutingMatrix,
+    /// The length of the fade, in samples
+    length: usize,
+    /// How many samples of the fade were already processed
+    progress: AtomicUsize,
+    /// Set once the audio thread asked for the DSP to be dropped
+    release_requested: AtomicBool,
+}
+
+impl FadingDsp {
+    pub fn new(dsp: faust_jit::SingletonDsp, routing: RoutingMatrix, length: usize) -> Self {
+        Self {
+            dsp,
+            routing,
+            length,
+            progress: AtomicUsize::new(0),
+            release_requested: AtomicBool::new(false),
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.progress.load(Ordering::Relaxed) >= self.length
+    }
+
+    /// True only the first time it is called once the fade is done, so that
+    /// the DSP is released only once
+    pub fn should_release(&self) -> bool {
+        self.is_done() && !self.release_requested.swap(true, Ordering::Relaxed)
+    }
+}
+
+/// What the audio thread needs to process a [`FadingDsp`]
+#[derive(Debug, Default)]
+pub struct SwapFader {
+    router: Router,
+    /// The input of the current buffer, and then the output of the fading DSP
+    old_output: Vec<Vec<f32>>,
+}
+
+impl SwapFader {
+    /// Allocates the buffers. Not to be called from the audio thread
+    pub fn resize(&mut self, channels: usize, max_buffer_size: usize) {
+        self.router.resize(max_buffer_size);
+        self.old_output = vec![vec![0.0; max_buffer_size]; channels];
+    }
+
+    /// Keeps the input of the buffer for the fading DSP. To be called before
+    /// the buffer is processed by the new DSP. Does not allocate
+    pub fn store_input(&mut self, main: &[&mut [f32]]) {
+        for (input, stored) in main.iter().zip(&mut self.old_output) {
+            if let Some(stored) = stored.get_mut(..input.len()) {
+                stored.copy_from_slice(input);
+            }
+        }
+    }
+
+    /// Processes the input kept by [`Self::store_input`] with the fading DSP,
+    /// and crossfades its output with `main`, which holds the output of the new
+    /// DSP. Does not allocate
+    pub fn fade<'a>(
+        &mut self,
+        fading: &FadingDsp,
+        main: &mut [&mut [f32]],
+        sidechain: impl Iterator<Item = &'a [f32]> + Clone,
+    ) {
+        let samples = main.first().map_or(0, |chan| chan.len());
+        if self.old_output.first().map_or(0, Vec::len) < samples {
+            // Not initialized for this buffer size:
+            return;
+        }
+        {
+            let mut chans: [&mut [f32]; crate::routing::MAX_DSP_CHANNELS] =
+                std::array::from_fn(|_| &mut [][..]);
+            let num_chans = self.old_output.len().min(chans.len());
+            for (chan, output) in chans.iter_mut().zip(&mut self.old_output) {
+                *chan = &mut output[..samples];
+            }
+            self.router.process(
+                &fading.dsp,
+                &fading.routing,
+                &mut chans[..num_chans],
+                sidechain,
+            );
+        }
+        let start = fading.progress.fetch_add(samples, Ordering::Relaxed);
+        for (new, old) in main.iter_mut().zip(&self.old_output) {
+            for (i, (n, o)) in new.iter_mut().zip(old).enumerate() {
+                // Equal-gain crossfade, as the old and new versions of a
+                // script usually output much the same signal:
+                let t = ((start + i) as f32 / fading.length as f32).min(1.0);
+                *n = *n * t + o * (1.0 - t);
+            }
+        }
+    }
+}